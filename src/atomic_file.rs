@@ -0,0 +1,223 @@
+//! Writes the state files so that a crash or a full disk never leaves them half-written
+
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+};
+
+use snafu::{whatever, ResultExt};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{error, warn};
+
+use crate::whatever::Whatever;
+
+/// How many previous versions of the file are kept next to it
+pub const BACKUP_COUNT: usize = 3;
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// `whitelist.json.bak.1` is the newest backup
+fn backup_path(path: &Path, number: usize) -> PathBuf {
+    with_suffix(path, &format!(".bak.{}", number))
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Shifts the backups by one, making the current file the newest backup
+async fn rotate_backups(path: &Path) -> io::Result<()> {
+    for number in (1..BACKUP_COUNT).rev() {
+        match fs::rename(backup_path(path, number), backup_path(path, number + 1)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    // copied, so that there is no moment without the file in place
+    let backup = backup_path(path, 1);
+    match fs::copy(path, &backup).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+        // the copy is only on the disk once it's synced, like the file itself
+        Ok(_) => fs::File::open(&backup).await?.sync_all().await,
+    }
+}
+
+/// Replaces the file contents atomically, keeping the previous versions as backups
+///
+/// The data is written to a temporary file and synced before being renamed over the old file.
+pub async fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+    let dir = parent_dir(path);
+    fs::create_dir_all(dir).await?;
+
+    let temp_path = with_suffix(path, ".tmp");
+    let mut file = fs::File::create(&temp_path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    rotate_backups(path).await?;
+    fs::rename(&temp_path, path).await?;
+    // the rename itself is only durable once the directory is synced
+    fs::File::open(dir).await?.sync_all().await?;
+
+    Ok(())
+}
+
+/// Why the contents of the file could not be used
+#[derive(Debug)]
+pub enum ParseError {
+    /// The file is damaged, one of the backups may still be fine
+    Corrupted(Whatever),
+    /// The file is fine, but can't be used, like the one written by a newer version.
+    /// Falling back to a backup would lose the newer data, so the reading fails
+    Unusable(Whatever),
+}
+
+/// Reads and parses the file, falling back to the newest backup that parses if it's corrupted
+///
+/// Returns `None` if the file does not exist.
+pub async fn read<T>(
+    path: &Path,
+    parse: impl Fn(&str) -> Result<T, ParseError>,
+) -> Result<Option<T>, Whatever> {
+    let main_error = match fs::read_to_string(path).await {
+        Ok(data) => match parse(&data) {
+            Ok(value) => return Ok(Some(value)),
+            Err(ParseError::Corrupted(e)) => e,
+            Err(ParseError::Unusable(e)) => {
+                return Err(e).with_whatever_context(|_| format!("Using {}", path.display()))
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).whatever_context("Reading from file"),
+    };
+    error!(
+        "{} is corrupted, looking for a backup: {}",
+        path.display(),
+        main_error
+    );
+
+    for number in 1..=BACKUP_COUNT {
+        let backup = backup_path(path, number);
+        let data = match fs::read_to_string(&backup).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                warn!("Could not read {}: {}", backup.display(), e);
+                continue;
+            }
+        };
+        match parse(&data) {
+            Ok(value) => {
+                warn!("Recovered {} from {}", path.display(), backup.display());
+                return Ok(Some(value));
+            }
+            Err(ParseError::Corrupted(e) | ParseError::Unusable(e)) => {
+                warn!("{} can't be used either: {}", backup.display(), e)
+            }
+        }
+    }
+
+    whatever!(
+        "{} is corrupted and there is no valid backup: {}",
+        path.display(),
+        main_error
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use snafu::FromString;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn parse_number(data: &str) -> Result<u32, ParseError> {
+        data.parse().map_err(|_| {
+            ParseError::Corrupted(Whatever::without_source(format!("Not a number: {}", data)))
+        })
+    }
+
+    async fn write_versions(path: &Path, versions: u32) {
+        for version in 1..=versions {
+            write(path, version.to_string().as_bytes()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_none() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+
+        assert_eq!(read(&path, parse_number).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reads_what_was_written() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nested").join("state.json");
+
+        write_versions(&path, 2).await;
+
+        assert_eq!(read(&path, parse_number).await.unwrap(), Some(2));
+        assert!(!with_suffix(&path, ".tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_newest_backups() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+
+        write_versions(&path, BACKUP_COUNT as u32 + 3).await;
+
+        let latest = BACKUP_COUNT as u32 + 3;
+        for number in 1..=BACKUP_COUNT {
+            let backup = fs::read_to_string(backup_path(&path, number))
+                .await
+                .unwrap();
+            assert_eq!(backup, (latest - number as u32).to_string());
+        }
+        assert!(!backup_path(&path, BACKUP_COUNT + 1).exists());
+    }
+
+    #[tokio::test]
+    async fn recovers_from_the_newest_valid_backup() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        write_versions(&path, 3).await;
+
+        fs::write(&path, "garbage").await.unwrap();
+        assert_eq!(read(&path, parse_number).await.unwrap(), Some(2));
+
+        fs::write(backup_path(&path, 1), "garbage").await.unwrap();
+        assert_eq!(read(&path, parse_number).await.unwrap(), Some(1));
+
+        fs::write(backup_path(&path, 2), "garbage").await.unwrap();
+        assert!(read(&path, parse_number).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unusable_file_is_not_replaced_by_a_backup() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        write_versions(&path, 2).await;
+
+        let result = read(&path, |data| match data {
+            "2" => Err(ParseError::Unusable(Whatever::without_source(
+                "Written by a newer version".to_string(),
+            ))),
+            data => parse_number(data),
+        })
+        .await;
+        assert!(result.is_err());
+    }
+}
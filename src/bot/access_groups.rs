@@ -0,0 +1,155 @@
+//! Lets the members of some telegram chats use the bot without being whitelisted one by one
+
+use std::time::Duration;
+
+use grammers_client::client::auth::InvocationError;
+use grammers_session::{PackedChat, PackedType};
+use grammers_tl_types as tl;
+use snafu::{OptionExt, ResultExt};
+use tracing::{debug, warn};
+
+use crate::{
+    bot::{roles::Role, BotContext, UserId},
+    whatever::Whatever,
+};
+
+/// How long the membership (or its absence) is remembered
+pub const ACCESS_GROUP_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Finds the chat of the access group, by its username or id
+///
+/// Bots can't look the chats up by id, so those are used as is, without an access hash.
+async fn resolve_access_group(context: &BotContext, chat: &str) -> Result<PackedChat, Whatever> {
+    if let Ok(id) = chat.parse::<i64>() {
+        return Ok(PackedChat {
+            ty: PackedType::Megagroup,
+            id,
+            access_hash: None,
+        });
+    }
+
+    let chat = context
+        .client
+        .resolve_username(chat.trim_start_matches('@'))
+        .await
+        .whatever_context("Resolving the access group")?
+        .with_whatever_context(|| format!("Access group {} does not exist", chat))?;
+    Ok(chat.pack())
+}
+
+/// Chats of the access groups with their roles, leaving out the ones that can't be resolved
+///
+/// The resolved chats are remembered, the others are tried again next time.
+/// Returns false next to the chats if some were left out.
+async fn access_group_chats(context: &BotContext) -> (Vec<(PackedChat, Role)>, bool) {
+    let mut resolved = context.access_group_chats.lock().await;
+    let mut chats = Vec::new();
+    let mut complete = true;
+    for (index, group) in context.access_groups.iter().enumerate() {
+        let chat = match resolved.get(&index) {
+            Some(chat) => *chat,
+            None => match resolve_access_group(context, &group.chat).await {
+                Ok(chat) => {
+                    resolved.insert(index, chat);
+                    chat
+                }
+                Err(e) => {
+                    warn!("Could not resolve the access group {}: {}", group.chat, e);
+                    complete = false;
+                    continue;
+                }
+            },
+        };
+        chats.push((chat, group.role));
+    }
+    (chats, complete)
+}
+
+/// Asks telegram whether the user is a member of the channel or supergroup
+async fn is_member(
+    context: &BotContext,
+    chat: &PackedChat,
+    user: &PackedChat,
+) -> Result<bool, Whatever> {
+    let request = tl::functions::channels::GetParticipant {
+        channel: tl::types::InputChannel {
+            channel_id: chat.id,
+            access_hash: chat.access_hash.unwrap_or(0),
+        }
+        .into(),
+        participant: tl::types::InputPeerUser {
+            user_id: user.id,
+            access_hash: user.access_hash.unwrap_or(0),
+        }
+        .into(),
+    };
+
+    let participant = match context.client.invoke(&request).await {
+        Ok(tl::enums::channels::ChannelParticipant::Participant(participant)) => {
+            participant.participant
+        }
+        Err(InvocationError::Rpc(e)) if e.name == "USER_NOT_PARTICIPANT" => return Ok(false),
+        Err(e) => return Err(e).whatever_context("Getting the chat participant"),
+    };
+
+    Ok(match participant {
+        tl::enums::ChannelParticipant::Left(_) => false,
+        // the restricted members are still members, unless they are kicked
+        tl::enums::ChannelParticipant::Banned(banned) => {
+            let tl::enums::ChatBannedRights::Rights(rights) = banned.banned_rights;
+            !banned.left && !rights.view_messages
+        }
+        _ => true,
+    })
+}
+
+/// Role the user gets from the first access group they are a member of, `None` if there is none
+///
+/// Without the access hash of the user, telegram may not tell. The answer is cached,
+/// so the users who join or leave a group may have to wait for [ACCESS_GROUP_CACHE_TTL].
+pub async fn access_group_role(
+    context: &BotContext,
+    user: UserId,
+    access_hash: Option<i64>,
+) -> Option<Role> {
+    if context.access_groups.is_empty() {
+        return None;
+    }
+
+    if let Some(role) = context.access_group_roles.lock().unwrap().get(&user) {
+        return *role;
+    }
+
+    let user_chat = PackedChat {
+        ty: PackedType::User,
+        id: user.0,
+        access_hash,
+    };
+    let (chats, mut complete) = access_group_chats(context).await;
+    let mut role = None;
+    for (chat, group_role) in chats {
+        match is_member(context, &chat, &user_chat).await {
+            Ok(true) => {
+                role = Some(group_role);
+                break;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Could not check the membership in {:?}: {}", chat, e);
+                complete = false;
+            }
+        }
+    }
+    // the user may be in a group that could not be checked, maybe it works next time
+    if role.is_none() && !complete {
+        return None;
+    }
+
+    debug!("{:?} got {:?} from the access groups", user, role);
+    context
+        .access_group_roles
+        .lock()
+        .unwrap()
+        .insert(user, role);
+    role
+}
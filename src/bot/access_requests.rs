@@ -0,0 +1,278 @@
+//! Lets the users without access ask the users managers for it
+
+use std::time::Duration;
+
+use grammers_client::{button, reply_markup, types::CallbackQuery, InputMessage};
+use grammers_session::{PackedChat, PackedType};
+use snafu::ResultExt;
+use tracing::{debug, info, warn};
+
+use crate::{
+    bot::{
+        callback::CallbackAction, lang::Lang, markdown, roles, scheduler::RequestKind,
+        whitelist::UserInfo, BotContext, UserId,
+    },
+    state_store::Collection,
+    whatever::Whatever,
+};
+
+/// How long a request waits for a decision. The user can't send another one until it expires
+pub const ACCESS_REQUEST_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// An access request sent to the users managers
+pub struct AccessRequest {
+    access_hash: i64,
+    state: RequestState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestState {
+    Pending,
+    /// One of the managers pressed a button, the decision is not stored yet
+    Deciding,
+    /// One of the managers already approved or denied it
+    Decided,
+}
+
+/// Keyboard attached to the [Lang::NoAccess] reply
+pub fn request_markup() -> reply_markup::Inline {
+    reply_markup::inline(vec![vec![button::inline(
+        Lang::ButtonRequestAccess.to_string(),
+        CallbackAction::RequestAccess.to_data(),
+    )]])
+}
+
+fn decision_markup(user: UserId) -> reply_markup::Inline {
+    reply_markup::inline(vec![vec![
+        button::inline(
+            Lang::ButtonApprove.to_string(),
+            CallbackAction::ApproveAccess(user).to_data(),
+        ),
+        button::inline(
+            Lang::ButtonDeny.to_string(),
+            CallbackAction::DenyAccess(user).to_data(),
+        ),
+    ]])
+}
+
+/// Remembers the access hash of the superuser who wrote to the bot, storing it if it's new
+pub async fn remember_superuser(
+    context: &BotContext,
+    superuser: UserId,
+    access_hash: i64,
+) -> Result<(), Whatever> {
+    let mut superuser_chats = context.superuser_chats.lock().await;
+    if superuser_chats.insert(superuser, access_hash) == Some(access_hash) {
+        return Ok(());
+    }
+    context
+        .state_store
+        .store_as(Collection::SuperuserChats, &*superuser_chats)
+        .await
+        .whatever_context("Storing the superuser chats")
+}
+
+/// Chats of the users allowed to decide on the access requests
+///
+/// Bots can't look the users up by id, so the access hash of a superuser is only known
+/// if they wrote to the bot at some point or are in the whitelist.
+async fn manager_chats(context: &BotContext) -> Vec<(UserId, PackedChat)> {
+    let superuser_chats = context.superuser_chats.lock().await.clone();
+    let whitelist = context.whitelist.lock().await;
+    let mut chats = context
+        .superusers
+        .iter()
+        .map(|&superuser| {
+            let access_hash = superuser_chats
+                .get(&superuser)
+                .copied()
+                .or_else(|| whitelist.get(&superuser).map(|info| info.access_hash));
+            let chat = PackedChat {
+                ty: PackedType::User,
+                id: superuser.0,
+                access_hash,
+            };
+            (superuser, chat)
+        })
+        .collect::<Vec<_>>();
+    chats.extend(
+        whitelist
+            .users()
+            .filter(|(user, info)| {
+                !context.superusers.contains(user)
+                    && roles::permissions(context, info.role).manage_users
+            })
+            .map(|(&user, info)| {
+                let chat = PackedChat {
+                    ty: PackedType::User,
+                    id: user.0,
+                    access_hash: Some(info.access_hash),
+                };
+                (user, chat)
+            }),
+    );
+
+    chats
+}
+
+/// Sends the request of the user who pressed the button to all the users managers
+pub async fn request_access(query: &CallbackQuery, context: &BotContext) -> Result<Lang, Whatever> {
+    let requester = query.sender();
+    let user = UserId(requester.id());
+
+    if roles::user_role(context, user).await.is_some() {
+        return Ok(Lang::InviteAlreadyAllowed);
+    }
+    let Some(access_hash) = requester.pack().access_hash else {
+        warn!("no access hash found for user id {:?}", user);
+        return Ok(Lang::WhitelistErrorNoAccessHash(user.0));
+    };
+
+    {
+        let mut access_requests = context.access_requests.lock().unwrap();
+        if access_requests.get(&user).is_some() {
+            debug!("Ignoring a repeated access request from {:?}", user);
+            return Ok(Lang::AccessRequestPending);
+        }
+        access_requests.insert(
+            user,
+            AccessRequest {
+                access_hash,
+                state: RequestState::Pending,
+            },
+        );
+    }
+
+    info!("Sending the access request of {:?} to the managers", user);
+    let username = requester
+        .username()
+        .map_or("-".to_string(), |username| format!("@{}", username));
+    let text = Lang::AccessRequestNotification(
+        markdown::escape(requester.name()),
+        markdown::escape(&username),
+        markdown::user_mention(user, &user.0.to_string()),
+    )
+    .to_string();
+    let message = InputMessage::markdown(text).reply_markup(&decision_markup(user));
+
+    let mut delivered = false;
+    for (manager, chat) in manager_chats(context).await {
+        let result = context
+            .scheduler
+            .run(Some(manager.0), RequestKind::Required, || {
+                context.client.send_message(chat, message.clone())
+            })
+            .await;
+        match result {
+            Ok(_) => delivered = true,
+            Err(e) => warn!("Could not send the access request to {:?}: {}", manager, e),
+        }
+    }
+
+    if !delivered {
+        // let them try again later instead of waiting for a request nobody saw
+        context.access_requests.lock().unwrap().remove(&user);
+        return Ok(Lang::AccessRequestFailed);
+    }
+
+    Ok(Lang::AccessRequestSent)
+}
+
+/// Approves or denies the access request of the `user`, only the first decision counts
+pub async fn decide(
+    query: &CallbackQuery,
+    context: &BotContext,
+    sender: UserId,
+    user: UserId,
+    approve: bool,
+) -> Result<Lang, Whatever> {
+    if !roles::can_manage_users(context, sender).await {
+        return Ok(Lang::CallbackNotAllowed);
+    }
+
+    let access_hash = {
+        let mut access_requests = context.access_requests.lock().unwrap();
+        let Some(request) = access_requests.get_mut(&user) else {
+            return Ok(Lang::CallbackExpired);
+        };
+        if request.state != RequestState::Pending {
+            return Ok(Lang::AccessRequestAlreadyDecided);
+        }
+        // keeps the other managers from deciding at the same time
+        request.state = RequestState::Deciding;
+        request.access_hash
+    };
+
+    let result = apply_decision(context, sender, user, access_hash, approve).await;
+    // the entry is kept until it expires, so that a denied user can't ask again right away.
+    // A decision that could not be stored can be made again
+    if let Some(request) = context.access_requests.lock().unwrap().get_mut(&user) {
+        request.state = match result {
+            Ok(_) => RequestState::Decided,
+            Err(_) => RequestState::Pending,
+        };
+    }
+    let decision = result?;
+
+    let notification = query
+        .load_message()
+        .await
+        .whatever_context("Loading the access request message")?;
+
+    // the buttons of the other managers stay, pressing them just says it's decided already
+    let edited_message = InputMessage::text(format!("{}\n\n{}", notification.text(), decision));
+    context
+        .scheduler
+        .run(
+            Some(notification.chat().id()),
+            RequestKind::Required,
+            || notification.edit(edited_message.clone()),
+        )
+        .await
+        .whatever_context("Removing buttons from the access request message")?;
+
+    Ok(decision)
+}
+
+/// Adds the user to the whitelist if approved, letting them know
+async fn apply_decision(
+    context: &BotContext,
+    sender: UserId,
+    user: UserId,
+    access_hash: i64,
+    approve: bool,
+) -> Result<Lang, Whatever> {
+    let decision = if approve {
+        info!("Access request of {:?} approved by {:?}", user, sender);
+        context
+            .whitelist
+            .lock()
+            .await
+            .insert(user, UserInfo::new(access_hash, Some(sender)))
+            .await
+            .whatever_context("Inserting user into whitelist")?;
+
+        let user_chat = PackedChat {
+            ty: PackedType::User,
+            id: user.0,
+            access_hash: Some(access_hash),
+        };
+        let message = InputMessage::from(Lang::AccessRequestApprovedNotification);
+        if let Err(e) = context
+            .scheduler
+            .run(Some(user.0), RequestKind::Required, || {
+                context.client.send_message(user_chat, message.clone())
+            })
+            .await
+        {
+            warn!("Could not notify {:?} about the approval: {}", user, e);
+        }
+
+        Lang::AccessRequestApproved
+    } else {
+        info!("Access request of {:?} denied by {:?}", user, sender);
+        Lang::AccessRequestDenied
+    };
+
+    Ok(decision)
+}
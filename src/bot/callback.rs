@@ -0,0 +1,340 @@
+//! Handles the presses of inline keyboard buttons
+
+use std::sync::Arc;
+
+use grammers_client::{
+    button, reply_markup,
+    types::{CallbackQuery, Message},
+    InputMessage,
+};
+use grammers_tl_types as tl;
+use snafu::ResultExt;
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    bot::{
+        access_requests,
+        jobs::{CancelResult, JobId},
+        lang::Lang,
+        picker, remember_user,
+        roles::{self, Role},
+        run_job,
+        scheduler::RequestKind,
+        upload::JobItem,
+        BotContext, JobRequest, UserId,
+    },
+    whatever::Whatever,
+};
+
+/// An action encoded in the callback data of a button
+///
+/// Telegram limits the callback data to 64 bytes, so it's kept short: `<action>:<argument>`
+#[derive(Debug)]
+pub enum CallbackAction {
+    Cancel(JobId),
+    /// Re-run a failed job with the same downloader
+    Retry(JobId),
+    /// Re-run a failed job with the next downloader supporting the url
+    NextDownloader(JobId),
+    /// Download the format with the given index from a pending choice
+    PickFormat(JobId, usize),
+    /// Ask the superusers for access, sent by the user who pressed the button
+    RequestAccess,
+    ApproveAccess(UserId),
+    DenyAccess(UserId),
+}
+
+impl CallbackAction {
+    pub fn to_data(&self) -> Vec<u8> {
+        match self {
+            CallbackAction::Cancel(job) => format!("cancel:{}", job),
+            CallbackAction::Retry(job) => format!("retry:{}", job),
+            CallbackAction::NextDownloader(job) => format!("next:{}", job),
+            CallbackAction::PickFormat(choice, index) => format!("fmt:{}:{}", choice, index),
+            CallbackAction::RequestAccess => "access".to_string(),
+            CallbackAction::ApproveAccess(user) => format!("approve:{}", user.0),
+            CallbackAction::DenyAccess(user) => format!("deny:{}", user.0),
+        }
+        .into_bytes()
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = std::str::from_utf8(data).ok()?;
+        let (action, argument) = data.split_once(':').unwrap_or((data, ""));
+
+        match action {
+            "cancel" => Some(CallbackAction::Cancel(argument.parse().ok()?)),
+            "retry" => Some(CallbackAction::Retry(argument.parse().ok()?)),
+            "next" => Some(CallbackAction::NextDownloader(argument.parse().ok()?)),
+            "fmt" => {
+                let (choice, index) = argument.split_once(':')?;
+                Some(CallbackAction::PickFormat(
+                    choice.parse().ok()?,
+                    index.parse().ok()?,
+                ))
+            }
+            "access" => Some(CallbackAction::RequestAccess),
+            "approve" => Some(CallbackAction::ApproveAccess(UserId(
+                argument.parse().ok()?,
+            ))),
+            "deny" => Some(CallbackAction::DenyAccess(UserId(argument.parse().ok()?))),
+            _ => None,
+        }
+    }
+}
+
+/// Keyboard attached to the status message of a running job
+pub fn cancel_markup(job: JobId) -> reply_markup::Inline {
+    reply_markup::inline(vec![vec![button::inline(
+        Lang::ButtonCancel.to_string(),
+        CallbackAction::Cancel(job).to_data(),
+    )]])
+}
+
+/// Buttons offered for a single failed item of a job
+pub struct RetryButtons {
+    pub job: JobId,
+    /// Number of the item in the job, `None` if the job had only one item
+    pub number: Option<usize>,
+    pub has_next_downloader: bool,
+}
+
+/// Keyboard attached to the error message of a failed job, one row per failed item
+pub fn retry_markup(buttons: &[RetryButtons]) -> reply_markup::Inline {
+    reply_markup::inline(
+        buttons
+            .iter()
+            .map(|buttons| {
+                let (retry_text, next_text) = match buttons.number {
+                    None => (Lang::ButtonRetry, Lang::ButtonNextDownloader),
+                    Some(number) => (
+                        Lang::ButtonRetryItem(number),
+                        Lang::ButtonNextDownloaderItem(number),
+                    ),
+                };
+
+                let mut row = vec![button::inline(
+                    retry_text.to_string(),
+                    CallbackAction::Retry(buttons.job).to_data(),
+                )];
+                if buttons.has_next_downloader {
+                    row.push(button::inline(
+                        next_text.to_string(),
+                        CallbackAction::NextDownloader(buttons.job).to_data(),
+                    ));
+                }
+                row
+            })
+            .collect(),
+    )
+}
+
+/// Rebuilds the keyboard of the message, dropping the buttons of the `job`
+fn rows_without_job(message: &Message, job: JobId) -> Vec<Vec<button::Inline>> {
+    let Some(tl::enums::ReplyMarkup::ReplyInlineMarkup(markup)) = &message.raw.reply_markup else {
+        return Vec::new();
+    };
+
+    markup
+        .rows
+        .iter()
+        .map(|tl::enums::KeyboardButtonRow::Row(row)| {
+            row.buttons
+                .iter()
+                .filter_map(|button| match button {
+                    tl::enums::KeyboardButton::Callback(button) => {
+                        match CallbackAction::parse(&button.data)? {
+                            CallbackAction::Retry(id) | CallbackAction::NextDownloader(id)
+                                if id == job =>
+                            {
+                                None
+                            }
+                            _ => Some(button::inline(button.text.clone(), button.data.clone())),
+                        }
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|row| !row.is_empty())
+        .collect()
+}
+
+/// Starts the failed job again in the background
+async fn retry_job(
+    query: &CallbackQuery,
+    context: &Arc<BotContext>,
+    sender: UserId,
+    job: JobId,
+    next_downloader: bool,
+) -> Result<Lang, Whatever> {
+    // the whitelist lock can't be taken while holding the failed jobs one
+    let is_manager = roles::can_manage_users(context, sender).await;
+
+    let mut failed_jobs = context.failed_jobs.lock().unwrap();
+    let Some(failed_job) = failed_jobs.get(&job) else {
+        return Ok(Lang::CallbackExpired);
+    };
+    if failed_job.owner != sender && !is_manager {
+        return Ok(Lang::CallbackNotAllowed);
+    }
+
+    // prevent the job from being started twice by pressing the button again
+    let failed_job = failed_jobs.remove(&job).unwrap();
+    drop(failed_jobs);
+
+    // the job is retried on behalf of its owner, with the role they have now
+    let role = match roles::user_role(context, failed_job.owner).await {
+        Some(role) => Some(role),
+        // the members of the groups that let everyone in are guests there
+        None if context
+            .groups
+            .get(&query.chat().id())
+            .map_or(false, |policy| policy.allow_all_members) =>
+        {
+            Some(Role::Guest)
+        }
+        None => None,
+    };
+    let authorized = match role {
+        Some(role) => {
+            roles::authorize_downloads(
+                context,
+                failed_job.owner,
+                role,
+                vec![failed_job.item.url.clone()],
+                next_downloader.then_some(&failed_job.item.downloader),
+                failed_job.item.options.clone(),
+            )
+            .await?
+        }
+        None => Err(roles::DownloadsDenied::Unsupported),
+    };
+    let (downloader, options) = match authorized {
+        Ok(mut authorized) => (authorized.items.remove(0).1, authorized.options),
+        Err(denied) => {
+            // the job can still be retried once the reason is gone
+            context.failed_jobs.lock().unwrap().insert(job, failed_job);
+            return Ok(match (role, denied) {
+                (None, _) => Lang::CallbackNotAllowed,
+                (Some(_), roles::DownloadsDenied::Unsupported) => Lang::CallbackExpired,
+                (Some(_), roles::DownloadsDenied::QuotaExceeded(limit)) => {
+                    Lang::QuotaExceeded(limit)
+                }
+            });
+        }
+    };
+
+    debug!(
+        "Retrying job {} for {} with {:?}",
+        job, failed_job.item.url, downloader
+    );
+
+    let error_message = query
+        .load_message()
+        .await
+        .whatever_context("Loading the error message")?;
+
+    // the buttons of this item are no longer useful, the other failed items can still be retried
+    let error_text = error_message.text().to_string();
+    let remaining_rows = rows_without_job(&error_message, job);
+    let edited_message = if remaining_rows.is_empty() {
+        InputMessage::text(error_text)
+    } else {
+        InputMessage::text(error_text).reply_markup(&reply_markup::inline(remaining_rows))
+    };
+    context
+        .scheduler
+        .run(
+            Some(error_message.chat().id()),
+            RequestKind::Required,
+            || error_message.edit(edited_message.clone()),
+        )
+        .await
+        .whatever_context("Removing buttons from the error message")?;
+
+    // the error message is a reply to the message with the link, the video should be sent as a reply to it too
+    let request_message = match error_message
+        .get_reply()
+        .await
+        .whatever_context("Loading the request message")?
+    {
+        Some(message) => message,
+        None => error_message,
+    };
+
+    let context = context.clone();
+    tokio::spawn(async move {
+        // error are logged by tracing instrument macro
+        let _ = run_job(
+            &context,
+            &request_message,
+            failed_job.owner,
+            JobRequest::single(JobItem {
+                downloader,
+                options,
+                ..failed_job.item
+            }),
+        )
+        .await;
+    });
+
+    Ok(Lang::CallbackRetrying)
+}
+
+#[instrument(skip_all, fields(sender_id = query.sender().id()), err(Debug))]
+pub async fn handle_callback_query(
+    query: CallbackQuery,
+    context: Arc<BotContext>,
+) -> Result<(), Whatever> {
+    remember_user(&context, query.sender().pack());
+    let sender = UserId(query.sender().id());
+
+    let answer = match CallbackAction::parse(query.data()) {
+        Some(CallbackAction::Cancel(job)) => {
+            debug!("Cancelling job {}", job);
+            match context
+                .jobs
+                .cancel(job, sender, roles::can_manage_users(&context, sender).await)
+            {
+                CancelResult::Cancelled => Lang::CallbackCancelling,
+                CancelResult::NotAllowed => Lang::CallbackNotAllowed,
+                CancelResult::NotFound => Lang::CallbackExpired,
+            }
+        }
+        Some(CallbackAction::Retry(job)) => retry_job(&query, &context, sender, job, false).await?,
+        Some(CallbackAction::NextDownloader(job)) => {
+            retry_job(&query, &context, sender, job, true).await?
+        }
+        Some(CallbackAction::PickFormat(choice, index)) => {
+            let picker_message = query
+                .load_message()
+                .await
+                .whatever_context("Loading the format keyboard message")?;
+            let is_manager = roles::can_manage_users(&context, sender).await;
+            picker::pick_format(picker_message, &context, sender, is_manager, choice, index).await?
+        }
+        Some(CallbackAction::RequestAccess) => {
+            access_requests::request_access(&query, &context).await?
+        }
+        Some(CallbackAction::ApproveAccess(user)) => {
+            access_requests::decide(&query, &context, sender, user, true).await?
+        }
+        Some(CallbackAction::DenyAccess(user)) => {
+            access_requests::decide(&query, &context, sender, user, false).await?
+        }
+        None => {
+            warn!("Got unknown callback data: {:?}", query.data());
+            Lang::CallbackExpired
+        }
+    };
+
+    query
+        .answer()
+        .text(answer.to_string())
+        .send()
+        .await
+        .whatever_context("Answering the callback query")?;
+
+    Ok(())
+}
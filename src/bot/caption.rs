@@ -0,0 +1,249 @@
+//! Renders the captions of the sent videos from the configured template
+
+use std::time::Duration;
+
+use url::Url;
+
+use crate::{bot::markdown, downloader::VideoMetadata};
+
+/// Telegram limit for the caption length (without the markup), in UTF-16 code units
+const CAPTION_LIMIT: usize = 1024;
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+fn format_count(count: u64) -> String {
+    match count {
+        0..=999 => count.to_string(),
+        1_000..=999_999 => format!("{:.1}K", count as f64 / 1e3),
+        1_000_000..=999_999_999 => format!("{:.1}M", count as f64 / 1e6),
+        _ => format!("{:.1}B", count as f64 / 1e9),
+    }
+}
+
+/// Plain text value of a metadata placeholder
+fn metadata_value(name: &str, metadata: &VideoMetadata, title: Option<&str>) -> Option<String> {
+    match name {
+        "title" => title.map(str::to_string),
+        "uploader" => metadata.uploader.clone(),
+        "upload_date" => metadata
+            .upload_date
+            .map(|date| date.format("%Y-%m-%d").to_string()),
+        "duration" => metadata.duration.map(format_duration),
+        "views" => metadata.view_count.map(format_count),
+        _ => None,
+    }
+}
+
+/// Substitutes the placeholders in a single template line
+///
+/// Returns `None` if any of the placeholders has no value, so that the line is left out.
+fn render_line(line: &str, lookup: &impl Fn(&str) -> Option<String>) -> Option<String> {
+    let mut result = String::new();
+    let mut rest = line;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('}') else {
+            // not a placeholder, just a lone brace
+            result.push_str(&rest[start..]);
+            rest = "";
+            break;
+        };
+        result.push_str(&lookup(&after[..end])?);
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+
+    Some(result)
+}
+
+fn render(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    template
+        .lines()
+        .filter_map(|line| render_line(line, &lookup))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Shortens the text by at least `by` UTF-16 code units, marking the cut with an ellipsis
+fn shorten(text: &str, by: usize) -> Option<String> {
+    let target = text.encode_utf16().count().checked_sub(by + 1)?;
+
+    let mut length = 0;
+    let mut result = text
+        .chars()
+        .take_while(|c| {
+            length += c.len_utf16();
+            length <= target
+        })
+        .collect::<String>();
+    result.push('…');
+
+    Some(result)
+}
+
+/// Renders the caption in markdown, with all the metadata values escaped
+///
+/// `notes` are plain text lines appended after the template, like the processing the video went through.
+/// The title is shortened if the caption does not fit into the telegram limit.
+/// If it still does not fit, only the link is left.
+pub fn render_caption(
+    template: &str,
+    canonical_url: &Url,
+    link_text: &str,
+    metadata: &VideoMetadata,
+    notes: &[String],
+) -> String {
+    let with_notes = |caption: String| {
+        notes.iter().fold(caption, |caption, note| {
+            format!("{}\n{}", caption, markdown::escape(note))
+        })
+    };
+
+    let link = markdown::link(canonical_url.as_str(), link_text);
+
+    let render_with_title = |title: Option<&str>| {
+        let caption = render(template, |name| match name {
+            "link" => Some(link.clone()),
+            name => metadata_value(name, metadata, title).map(|value| markdown::escape(&value)),
+        });
+        if caption.trim().is_empty() {
+            with_notes(link.clone())
+        } else {
+            with_notes(caption)
+        }
+    };
+
+    // the markup is not counted by telegram, so the limit is checked on the text as the user sees it
+    let caption = render_with_title(metadata.title.as_deref());
+    let overflow = markdown::visible_length(&caption).saturating_sub(CAPTION_LIMIT);
+    if overflow == 0 {
+        return caption;
+    }
+
+    let title = metadata
+        .title
+        .as_deref()
+        .and_then(|title| shorten(title, overflow));
+    let caption = render_with_title(title.as_deref());
+    if markdown::visible_length(&caption) > CAPTION_LIMIT {
+        return with_notes(link);
+    }
+    caption
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn metadata() -> VideoMetadata {
+        VideoMetadata {
+            title: Some("A video. Really!".to_string()),
+            uploader: Some("some_channel".to_string()),
+            upload_date: NaiveDate::from_ymd_opt(2024, 5, 17),
+            duration: Some(Duration::from_secs(3725)),
+            view_count: Some(1_234_567),
+        }
+    }
+
+    fn url() -> Url {
+        Url::parse("https://youtu.be/abc").unwrap()
+    }
+
+    #[test]
+    fn substitutes_and_escapes_placeholders() {
+        let caption = render_caption(
+            "*{title}* by {uploader}\n{upload_date} {duration} {views}\n{link}",
+            &url(),
+            "link",
+            &metadata(),
+            &[],
+        );
+        assert_eq!(
+            caption,
+            "*A video\\. Really\\!* by some\\_channel\n2024\\-05\\-17 1:02:05 1\\.2M\n[link](https://youtu.be/abc)"
+        );
+    }
+
+    #[test]
+    fn leaves_out_lines_without_values() {
+        let metadata = VideoMetadata {
+            title: Some("title".to_string()),
+            ..Default::default()
+        };
+        let caption = render_caption(
+            "{title}\n👤 {uploader}\n{link}",
+            &url(),
+            "l",
+            &metadata,
+            &[],
+        );
+        assert_eq!(caption, "title\n[l](https://youtu.be/abc)");
+    }
+
+    #[test]
+    fn appends_escaped_notes() {
+        let caption = render_caption("{link}", &url(), "l", &metadata(), &["note.".to_string()]);
+        assert_eq!(caption, "[l](https://youtu.be/abc)\nnote\\.");
+    }
+
+    #[test]
+    fn falls_back_to_the_link_when_empty() {
+        let caption = render_caption("{views}", &url(), "l", &VideoMetadata::default(), &[]);
+        assert_eq!(caption, "[l](https://youtu.be/abc)");
+    }
+
+    #[test]
+    fn shortens_the_title_to_fit() {
+        let metadata = VideoMetadata {
+            title: Some("a".repeat(2000)),
+            ..Default::default()
+        };
+        let caption = render_caption("*{title}*\n{link}", &url(), "link", &metadata, &[]);
+
+        assert_eq!(markdown::visible_length(&caption), CAPTION_LIMIT);
+        assert!(caption.starts_with("*aaa"));
+        assert!(caption.ends_with("…*\n[link](https://youtu.be/abc)"));
+    }
+
+    #[test]
+    fn markup_does_not_count_towards_the_limit() {
+        // fits exactly, but only when the markup and the escaping are not counted
+        let title = ".".repeat(CAPTION_LIMIT - 5);
+        let metadata = VideoMetadata {
+            title: Some(title.clone()),
+            ..Default::default()
+        };
+        let caption = render_caption("*{title}*\n{link}", &url(), "link", &metadata, &[]);
+
+        assert_eq!(
+            caption,
+            format!(
+                "*{}*\n[link](https://youtu.be/abc)",
+                markdown::escape(&title)
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_only_the_link_if_nothing_else_fits() {
+        let metadata = VideoMetadata {
+            uploader: Some("u".repeat(2000)),
+            ..Default::default()
+        };
+        let caption = render_caption("{uploader}\n{link}", &url(), "link", &metadata, &[]);
+        assert_eq!(caption, "[link](https://youtu.be/abc)");
+    }
+}
@@ -1,18 +1,26 @@
-use std::sync::Arc;
-
+use chrono::{DateTime, Duration, Utc};
 use grammers_client::{
     client::auth::InvocationError,
     types::{Chat, Message, User},
     Client, InputMessage,
 };
 use grammers_session::PackedChat;
-use grammers_tl_types::types::MessageEntityBotCommand;
-use snafu::{OptionExt, ResultExt, Snafu};
-use tokio::sync::Mutex;
-use tracing::{debug, info, warn};
+use grammers_tl_types::{self as tl, types::MessageEntityBotCommand};
+use snafu::{whatever, ResultExt, Snafu};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    bot::{lang::Lang, markdown, whitelist::UserInfo, UserId},
+    bot::{
+        entities::split_bot_command,
+        invites::Invite,
+        lang::Lang,
+        markdown,
+        options::OutputMode,
+        roles::{self, Permissions, Role},
+        whitelist::UserInfo,
+        BotContext, UserId,
+    },
+    state_store::Collection,
     whatever::Whatever,
 };
 
@@ -40,10 +48,163 @@ impl Alias {
     }
 }
 
+/// A user the command is about
+#[derive(Debug)]
+enum UserRef {
+    Alias(Alias),
+    /// Only the users the bot has seen recently or knows from the whitelist have an access hash
+    Id(UserId),
+    /// The user the replied-to message was forwarded from, or whose contact is shared in it
+    Reply,
+}
+
+struct ResolvedUser {
+    id: UserId,
+    /// `None` if no source provided it
+    access_hash: Option<i64>,
+}
+
+impl UserRef {
+    fn parse(arg: &str) -> Self {
+        match arg.parse::<i64>() {
+            Ok(id) => Self::Id(UserId(id)),
+            Err(_) => Self::Alias(Alias::parse(arg)),
+        }
+    }
+
+    /// Returns `None` if there is no such user, [Self::not_found_reply] explains why
+    async fn resolve(
+        &self,
+        context: &BotContext,
+        message: &Message,
+    ) -> Result<Option<ResolvedUser>, Whatever> {
+        match self {
+            Self::Alias(alias) => {
+                let user = alias
+                    .resolve(&context.client)
+                    .await
+                    .whatever_context("Resolving the user alias")?;
+                Ok(user.map(|user| ResolvedUser {
+                    id: UserId(user.id()),
+                    access_hash: PackedChat::from(user).access_hash,
+                }))
+            }
+            Self::Id(id) => {
+                let known = context.known_users.lock().unwrap().get(id).copied();
+                let access_hash = match known {
+                    Some(access_hash) => Some(access_hash),
+                    None => context
+                        .whitelist
+                        .lock()
+                        .await
+                        .get(id)
+                        .map(|info| info.access_hash),
+                };
+                Ok(Some(ResolvedUser {
+                    id: *id,
+                    access_hash,
+                }))
+            }
+            Self::Reply => user_from_reply(context, message).await,
+        }
+    }
+
+    fn not_found_reply(&self) -> Lang {
+        match self {
+            Self::Alias(_) | Self::Id(_) => Lang::WhitelistErrorAliasResolve,
+            Self::Reply => Lang::WhitelistErrorNoUserInReply,
+        }
+    }
+}
+
+/// Finds the user the replied-to message was forwarded from, or whose contact it shares
+async fn user_from_reply(
+    context: &BotContext,
+    message: &Message,
+) -> Result<Option<ResolvedUser>, Whatever> {
+    let Some(reply) = message
+        .get_reply()
+        .await
+        .whatever_context("Loading the replied-to message")?
+    else {
+        return Ok(None);
+    };
+
+    // users who hide their forwards only leave their name
+    let forwarded_from = match &reply.raw.fwd_from {
+        Some(tl::enums::MessageFwdHeader::Header(header)) => match &header.from_id {
+            Some(tl::enums::Peer::User(peer)) => Some(peer.user_id),
+            _ => None,
+        },
+        None => None,
+    };
+    let contact = match &reply.raw.media {
+        // contacts without a telegram account have no user id
+        Some(tl::enums::MessageMedia::Contact(contact)) if contact.user_id != 0 => {
+            Some(contact.user_id)
+        }
+        _ => None,
+    };
+    let Some(user_id) = forwarded_from.or(contact) else {
+        return Ok(None);
+    };
+
+    // telegram lets refer to a user through a message they appear in, which gives out the access hash
+    let request = tl::functions::users::GetUsers {
+        id: vec![tl::types::InputUserFromMessage {
+            peer: reply.chat().pack().to_input_peer(),
+            msg_id: reply.id(),
+            user_id,
+        }
+        .into()],
+    };
+    let access_hash = match context.client.invoke(&request).await {
+        Ok(users) => users.into_iter().find_map(|user| match user {
+            tl::enums::User::User(user) => user.access_hash,
+            tl::enums::User::Empty(_) => None,
+        }),
+        Err(e) => {
+            warn!("Could not get the user {} from the message: {}", user_id, e);
+            None
+        }
+    };
+
+    Ok(Some(ResolvedUser {
+        id: UserId(user_id),
+        access_hash,
+    }))
+}
+
+/// Parses durations like `30m`, `12h` or `7d`
+fn parse_duration(arg: &str) -> Option<Duration> {
+    let unit_start = arg.find(|c: char| !c.is_ascii_digit())?;
+    let amount = arg[..unit_start].parse::<i64>().ok()?;
+    match &arg[unit_start..] {
+        "m" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
 enum SuperuserCommand {
-    WhitelistInsert(Alias),
-    WhitelistRemove(Alias),
+    /// Adds the user, optionally only for some time
+    WhitelistInsert(UserRef, Option<Duration>),
+    WhitelistRemove(UserRef),
     WhitelistGet,
+    /// Creates an invite, optionally limited to a number of uses and a lifetime
+    InviteCreate {
+        uses: Option<u32>,
+        lifetime: Option<Duration>,
+    },
+    InviteRevoke(String),
+    InviteList,
+    SetRole(UserRef, Role),
     Help,
 }
 
@@ -61,38 +222,164 @@ enum CommandParseError {
     },
 }
 
+/// Extracts the command name (without the bot username) and its arguments from the message
+fn split_command(
+    command: &MessageEntityBotCommand,
+    message: &Message,
+) -> Result<(String, Vec<String>), Whatever> {
+    let text = message.text();
+    let text = text.encode_utf16().collect::<Vec<_>>();
+    let command_text = &text[command.offset as usize..(command.offset + command.length) as usize];
+    let command_text =
+        String::from_utf16(command_text).whatever_context("Parsing command from message")?;
+    let args = &text[(command.offset + command.length) as usize..];
+    let args = String::from_utf16(args)
+        .whatever_context("Parsing arguments for the command from message")?;
+    let args = args.split_whitespace().map(str::to_string).collect();
+    let (command, _) = split_bot_command(&command_text);
+
+    Ok((command.to_string(), args))
+}
+
+/// Commands available to everyone who can use the bot
+enum UserCommand {
+    /// Shows or sets the output mode of the chat
+    OutputMode(Option<String>),
+}
+
+impl UserCommand {
+    /// Returns `None` if it's not a user command
+    fn parse(
+        command: &MessageEntityBotCommand,
+        message: &Message,
+    ) -> Result<Option<Self>, Whatever> {
+        let (command, args) = split_command(command, message)?;
+
+        Ok(match command.as_str() {
+            "/mode" => Some(Self::OutputMode(args.into_iter().next())),
+            _ => None,
+        })
+    }
+}
+
+/// Handles the commands available to everyone, returns `None` if the command is not one of them
+pub async fn handle_user_command(
+    context: &BotContext,
+    permissions: &Permissions,
+    command: &MessageEntityBotCommand,
+    message: &Message,
+) -> Result<Option<InputMessage>, Whatever> {
+    let Some(command) = UserCommand::parse(command, message)? else {
+        return Ok(None);
+    };
+
+    let reply = match command {
+        UserCommand::OutputMode(None) => {
+            let mode = context
+                .chat_output_modes
+                .lock()
+                .await
+                .get(&message.chat().id())
+                .copied()
+                .unwrap_or_default();
+            Lang::OutputModeCurrent(mode.name())
+        }
+        // in a group the mode affects everyone, so not everyone can change it
+        UserCommand::OutputMode(Some(_))
+            if !matches!(message.chat(), Chat::User(_)) && !permissions.manage_users =>
+        {
+            Lang::OutputModeNotAllowed
+        }
+        UserCommand::OutputMode(Some(mode)) => match OutputMode::parse(&mode) {
+            Some(mode) => {
+                info!("Setting the output mode of the chat to {:?}", mode);
+                let mut modes = context.chat_output_modes.lock().await;
+                modes.insert(message.chat().id(), mode);
+                context
+                    .state_store
+                    .store_as(Collection::ChatOutputModes, &*modes)
+                    .await
+                    .whatever_context("Storing the chat output modes")?;
+                Lang::OutputModeSet(mode.name())
+            }
+            None => Lang::OutputModeUnknown,
+        },
+    };
+
+    Ok(Some(reply.into()))
+}
+
 impl SuperuserCommand {
     fn parse(
         command: &MessageEntityBotCommand,
         message: &Message,
     ) -> Result<Self, CommandParseError> {
-        let text = message.text();
-        let text = text.encode_utf16().collect::<Vec<_>>();
-        let command_text =
-            &text[command.offset as usize..(command.offset + command.length) as usize];
-        let command_text =
-            String::from_utf16(command_text).whatever_context("Parsing command from message")?;
-        let args = &text[(command.offset + command.length) as usize..];
-        let args = String::from_utf16(args)
-            .whatever_context("Parsing arguments for the command from message")?;
-        let mut args = args.split_whitespace();
-        // expect??
-        let command = command_text
-            .split('@')
-            .next()
-            .whatever_context("Split produced no elements for some reason")?;
-
-        match command {
+        let (command, args) =
+            split_command(command, message).whatever_context("Splitting the command")?;
+        let mut args = args.iter().map(String::as_str);
+
+        match command.as_str() {
             "/whitelist" | "/whitelist_get" => Ok(Self::WhitelistGet),
             "/whitelist_add" => {
-                let arg = args.next().ok_or(CommandParseError::NoArgumentsProvided)?;
-                let alias = Alias::parse(arg);
-                Ok(Self::WhitelistInsert(alias))
+                let args = args.collect::<Vec<_>>();
+                // `/whitelist_add 7d` in a reply is about the replied-to user
+                let (user, lifetime) = match args.as_slice() {
+                    [user, lifetime] => (UserRef::parse(user), Some(*lifetime)),
+                    [lifetime] if parse_duration(lifetime).is_some() => {
+                        (UserRef::Reply, Some(*lifetime))
+                    }
+                    [user] => (UserRef::parse(user), None),
+                    [] => (UserRef::Reply, None),
+                    _ => return Err(CommandParseError::IncorrectArguments),
+                };
+                if matches!(user, UserRef::Reply) && message.reply_to_message_id().is_none() {
+                    return Err(CommandParseError::NoArgumentsProvided);
+                }
+                let lifetime = lifetime
+                    .map(|arg| parse_duration(arg).ok_or(CommandParseError::IncorrectArguments))
+                    .transpose()?;
+                Ok(Self::WhitelistInsert(user, lifetime))
             }
             "/whitelist_remove" => {
+                let user = match args.next() {
+                    Some(arg) => UserRef::parse(arg),
+                    None if message.reply_to_message_id().is_some() => UserRef::Reply,
+                    None => return Err(CommandParseError::NoArgumentsProvided),
+                };
+                Ok(Self::WhitelistRemove(user))
+            }
+            "/invite" => {
+                let (mut uses, mut lifetime) = (None, None);
+                // `/invite 5 7d`, both are optional and can go in any order
+                for arg in args {
+                    match arg.parse::<u32>() {
+                        Ok(0) => return Err(CommandParseError::IncorrectArguments),
+                        Ok(arg) => uses = Some(arg),
+                        Err(_) => {
+                            lifetime = Some(
+                                parse_duration(arg).ok_or(CommandParseError::IncorrectArguments)?,
+                            )
+                        }
+                    }
+                }
+                Ok(Self::InviteCreate { uses, lifetime })
+            }
+            "/invite_revoke" => {
                 let arg = args.next().ok_or(CommandParseError::NoArgumentsProvided)?;
-                let alias = Alias::parse(arg);
-                Ok(Self::WhitelistRemove(alias))
+                Ok(Self::InviteRevoke(arg.to_string()))
+            }
+            "/invites" => Ok(Self::InviteList),
+            "/role" => {
+                // `/role admin` in a reply is about the replied-to user
+                let (user, role) = match (args.next(), args.next()) {
+                    (Some(user), Some(role)) => (UserRef::parse(user), role),
+                    (Some(role), None) if message.reply_to_message_id().is_some() => {
+                        (UserRef::Reply, role)
+                    }
+                    _ => return Err(CommandParseError::NoArgumentsProvided),
+                };
+                let role = Role::parse(role).ok_or(CommandParseError::IncorrectArguments)?;
+                Ok(Self::SetRole(user, role))
             }
             "/help" => Ok(Self::Help),
             _ => Err(CommandParseError::UnknownCommand),
@@ -101,11 +388,13 @@ impl SuperuserCommand {
 }
 
 pub async fn handle_command(
-    client: &Client,
+    context: &BotContext,
     command: &MessageEntityBotCommand,
     message: &Message,
-    whitelist: Arc<Mutex<crate::bot::whitelist::Whitelist>>,
 ) -> Result<InputMessage, Whatever> {
+    let client = &context.client;
+    let whitelist = &context.whitelist;
+
     let command = match SuperuserCommand::parse(command, message) {
         Ok(command) => command,
         Err(e) => {
@@ -120,55 +409,51 @@ pub async fn handle_command(
     };
 
     let reply = match command {
-        SuperuserCommand::WhitelistInsert(alias) => {
-            let Some(user) = alias
-                .resolve(client)
-                .await
-                .whatever_context("Resolving the user alias")?
-            else {
-                info!("Got username, but couldn't resolve: {}", alias.0);
-                return Ok(Lang::WhitelistErrorAliasResolve.into());
+        SuperuserCommand::WhitelistInsert(user_ref, lifetime) => {
+            let Some(user) = user_ref.resolve(context, message).await? else {
+                info!("Couldn't resolve the user: {:?}", user_ref);
+                return Ok(user_ref.not_found_reply().into());
             };
-            info!(
-                "Adding into whitelist user (name: {:?}, id: {})",
-                user.username(),
-                user.id()
-            );
-            let Some(access_hash) = grammers_session::PackedChat::from(user.clone()).access_hash
-            else {
-                warn!("no access hash found for user id {:?}", user.id());
-                return Ok(Lang::WhitelistErrorNoAccessHash(user.id()).into());
+            info!("Adding into whitelist user {:?} ({:?})", user.id, user_ref);
+            let Some(access_hash) = user.access_hash else {
+                warn!("no access hash found for user id {:?}", user.id);
+                return Ok(Lang::WhitelistErrorNoAccessHash(user.id.0).into());
+            };
+            let expires_at = lifetime.map(|lifetime| Utc::now() + lifetime);
+            let mut whitelist = whitelist.lock().await;
+            // adding a known user again refreshes the access hash and the expiration,
+            // but keeps the role and who added them when
+            let info = match whitelist.get(&user.id) {
+                Some(info) => info.renewed(access_hash, expires_at),
+                None => UserInfo {
+                    expires_at,
+                    ..UserInfo::new(access_hash, message.sender().map(|s| UserId(s.id())))
+                },
             };
             let added = whitelist
-                .lock()
-                .await
-                .insert(UserId(user.id()), UserInfo { access_hash })
+                .insert(user.id, info)
                 .await
                 .whatever_context("Inserting user into whitelist")?;
-            if added {
-                Lang::WhitelistAddOk
-            } else {
-                Lang::WhitelistAddKnown
+            match (added, expires_at) {
+                (true, None) => Lang::WhitelistAddOk,
+                (true, Some(expires_at)) => Lang::WhitelistAddOkUntil(format_time(expires_at)),
+                (false, None) => Lang::WhitelistAddKnown,
+                (false, Some(expires_at)) => Lang::WhitelistAddKnownUntil(format_time(expires_at)),
             }
         }
-        SuperuserCommand::WhitelistRemove(alias) => {
-            let Some(user) = alias
-                .resolve(client)
-                .await
-                .whatever_context("Resolving the user alias")?
-            else {
-                info!("Got username, but couldn't resolve: {}", alias.0);
-                return Ok(Lang::WhitelistErrorAliasResolve.into());
+        SuperuserCommand::WhitelistRemove(user_ref) => {
+            let Some(user) = user_ref.resolve(context, message).await? else {
+                info!("Couldn't resolve the user: {:?}", user_ref);
+                return Ok(user_ref.not_found_reply().into());
             };
             info!(
-                "Removing from whitelist user (name: {:?}, id: {})",
-                user.username(),
-                user.id()
+                "Removing from whitelist user {:?} ({:?})",
+                user.id, user_ref
             );
             let removed = whitelist
                 .lock()
                 .await
-                .remove(UserId(user.id()))
+                .remove(user.id)
                 .await
                 .whatever_context("Removing user from whitelist")?;
             if removed.is_some() {
@@ -180,9 +465,8 @@ pub async fn handle_command(
         SuperuserCommand::WhitelistGet => {
             debug!("Showing whitelist");
             let whitelist = whitelist.lock().await;
-            let user_ids = whitelist.users();
-            let mut users_string = String::with_capacity(user_ids.len());
-            for (user_id, user_info) in user_ids.iter() {
+            let mut users_string = String::new();
+            for (user_id, user_info) in whitelist.users() {
                 let chat = client
                     .unpack_chat(PackedChat {
                         ty: grammers_session::PackedType::User,
@@ -199,13 +483,170 @@ pub async fn handle_command(
                     None => markdown::user_mention(*user_id, "<cringe cuteness drowning femboy>"),
                 };
 
-                users_string.push_str(&format!("{}\n\\\n", Lang::WhitelistListItem(user_tag)));
+                let item = match user_info.expires_at {
+                    None => Lang::WhitelistListItem(user_tag),
+                    Some(expires_at) => Lang::WhitelistListItemUntil(
+                        user_tag,
+                        markdown::escape(&format_time(expires_at)),
+                    ),
+                };
+                users_string.push_str(&format!("{}\n\\\n", item));
             }
             let reply_md = format!("{}:\\\n{}\n", Lang::WhitelistListHead, users_string);
 
             return Ok(InputMessage::markdown(&reply_md));
         }
+        SuperuserCommand::InviteCreate { uses, lifetime } => {
+            let Some(bot_username) = context.me.username() else {
+                whatever!("The bot has no username to make a deep link with");
+            };
+            let invite = Invite {
+                uses_left: uses,
+                expires_at: lifetime.map(|lifetime| Utc::now() + lifetime),
+                created_by: UserId(message.sender().map_or(0, |sender| sender.id())),
+            };
+            info!("Creating an invite: {:?}", invite);
+            let code = context
+                .invites
+                .lock()
+                .await
+                .create(invite)
+                .await
+                .whatever_context("Creating the invite")?;
+            Lang::InviteCreated(format!("https://t.me/{}?start={}", bot_username, code))
+        }
+        SuperuserCommand::InviteRevoke(code) => {
+            info!("Revoking the invite {}", code);
+            let revoked = context
+                .invites
+                .lock()
+                .await
+                .revoke(&code)
+                .await
+                .whatever_context("Revoking the invite")?;
+            if revoked {
+                Lang::InviteRevokeOk
+            } else {
+                Lang::InviteRevokeUnknown
+            }
+        }
+        SuperuserCommand::InviteList => {
+            debug!("Showing invites");
+            let invites = context.invites.lock().await;
+            let mut lines = vec![Lang::InviteListHead.to_string()];
+            for (code, invite) in invites.valid_invites() {
+                let uses = invite
+                    .uses_left
+                    .map_or("∞".to_string(), |uses| uses.to_string());
+                let expires_at = invite.expires_at.map_or("never".to_string(), format_time);
+                lines.push(Lang::InviteListItem(code.clone(), uses, expires_at).to_string());
+            }
+            return Ok(InputMessage::text(lines.join("\n")));
+        }
+        SuperuserCommand::SetRole(user_ref, role) => {
+            let Some(user) = user_ref.resolve(context, message).await? else {
+                info!("Couldn't resolve the user: {:?}", user_ref);
+                return Ok(user_ref.not_found_reply().into());
+            };
+            if context.superusers.contains(&user.id) {
+                return Ok(Lang::RoleSuperuser.into());
+            }
+            info!(
+                "Setting the role of user {:?} ({:?}) to {:?}",
+                user.id, user_ref, role
+            );
+            let updated = whitelist
+                .lock()
+                .await
+                .set_role(user.id, role)
+                .await
+                .whatever_context("Setting the user role")?;
+            if updated {
+                Lang::RoleSetOk(role.name())
+            } else {
+                Lang::RoleSetUnknown
+            }
+        }
         SuperuserCommand::Help => Lang::CommandHelp,
     };
     Ok(reply.into())
 }
+
+/// Redeems the invite from a `/start <code>` deep link, returns `None` for any other command
+///
+/// The access hash is taken from the message itself, so it works for the users that can't be resolved by username.
+pub async fn handle_start_command(
+    context: &BotContext,
+    command: &MessageEntityBotCommand,
+    message: &Message,
+) -> Result<Option<InputMessage>, Whatever> {
+    let (command, args) = split_command(command, message)?;
+    let (Some(code), "/start") = (args.first(), command.as_str()) else {
+        return Ok(None);
+    };
+    let Some(sender) = message.sender() else {
+        return Ok(None);
+    };
+    let user = UserId(sender.id());
+
+    // a used up invite would be wasted on them
+    if roles::user_role(context, user).await.is_some() {
+        return Ok(Some(Lang::InviteAlreadyAllowed.into()));
+    }
+
+    let Some(access_hash) = sender.pack().access_hash else {
+        warn!("no access hash found for user id {:?}", user);
+        return Ok(Some(Lang::WhitelistErrorNoAccessHash(user.0).into()));
+    };
+
+    // the invite is only used up once the user is in, the lock keeps the others from using it meanwhile
+    let mut invites = context.invites.lock().await;
+    let Some(invite) = invites.get_valid(code) else {
+        info!("User {:?} tried to redeem an invalid invite", user);
+        return Ok(Some(Lang::InviteInvalid.into()));
+    };
+
+    info!(
+        "Adding into whitelist user {:?} invited with {}",
+        user, code
+    );
+    context
+        .whitelist
+        .lock()
+        .await
+        .insert(user, UserInfo::new(access_hash, Some(invite.created_by)))
+        .await
+        .whatever_context("Inserting user into whitelist")?;
+
+    if let Err(e) = invites.redeem(code).await {
+        // the user is in already, there is nothing to gain from failing
+        error!("Could not store the redeemed invite {}: {}", code, e);
+    }
+
+    Ok(Some(Lang::InviteRedeemed.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_duration("12h"), Some(Duration::hours(12)));
+        assert_eq!(parse_duration("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_duration("2w"), Some(Duration::weeks(2)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert_eq!(parse_duration("7"), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("7y"), None);
+        assert_eq!(parse_duration("7dd"), None);
+        assert_eq!(parse_duration("-7d"), None);
+        assert_eq!(parse_duration("@user"), None);
+        // too large for chrono
+        assert_eq!(parse_duration("99999999999999w"), None);
+    }
+}
@@ -0,0 +1,145 @@
+//! Time limit of handling a video, which stops counting while the video is re-encoded
+//!
+//! Re-encoding takes time proportional to the video duration, so it has its own limit instead.
+
+use std::{future::Future, sync::Mutex, time::Duration};
+
+use snafu::whatever;
+use tokio::{
+    select,
+    sync::Notify,
+    time::{sleep_until, Instant},
+};
+
+use crate::whatever::Whatever;
+
+pub struct Deadline {
+    state: Mutex<State>,
+    resumed: Notify,
+}
+
+struct State {
+    deadline: Instant,
+    /// Set while the clock is stopped
+    paused_since: Option<Instant>,
+}
+
+impl Deadline {
+    pub fn after(duration: Duration) -> Self {
+        Self {
+            state: Mutex::new(State {
+                deadline: Instant::now() + duration,
+                paused_since: None,
+            }),
+            resumed: Notify::new(),
+        }
+    }
+
+    /// Completes once the time is up, never while the clock is stopped
+    async fn expired(&self) {
+        loop {
+            let deadline = {
+                let state = self.state.lock().unwrap();
+                state.paused_since.is_none().then_some(state.deadline)
+            };
+            match deadline {
+                Some(deadline) if Instant::now() >= deadline => return,
+                // the deadline may have moved in the meantime, so it's checked again
+                Some(deadline) => sleep_until(deadline).await,
+                None => self.resumed.notified().await,
+            }
+        }
+    }
+
+    /// Runs the future, returning `None` if the time is up first
+    pub async fn limit<F: Future>(&self, fut: F) -> Option<F::Output> {
+        select! {
+            output = fut => Some(output),
+            _ = self.expired() => None,
+        }
+    }
+
+    /// Runs the future with the clock stopped, but for no longer than `limit`
+    pub async fn pause_for<F: Future>(
+        &self,
+        limit: Duration,
+        fut: F,
+    ) -> Result<F::Output, Whatever> {
+        let _guard = self.pause();
+        match tokio::time::timeout(limit, fut).await {
+            Ok(output) => Ok(output),
+            Err(_) => whatever!("Took longer than {:?}", limit),
+        }
+    }
+
+    fn pause(&self) -> PauseGuard<'_> {
+        self.state.lock().unwrap().paused_since = Some(Instant::now());
+        PauseGuard(self)
+    }
+}
+
+/// Starts the clock again when dropped, moving the deadline by the time it was stopped for
+struct PauseGuard<'a>(&'a Deadline);
+
+impl Drop for PauseGuard<'_> {
+    fn drop(&mut self) {
+        {
+            let mut state = self.0.state.lock().unwrap();
+            if let Some(paused_since) = state.paused_since.take() {
+                state.deadline += paused_since.elapsed();
+            }
+        }
+        self.0.resumed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use tokio::time::sleep;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_after_the_timeout() {
+        let deadline = Deadline::after(TIMEOUT);
+        let start = Instant::now();
+
+        assert_eq!(deadline.limit(pending::<()>()).await, None);
+        assert_eq!(start.elapsed(), TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_count_the_paused_time() {
+        let deadline = Deadline::after(TIMEOUT);
+
+        let result = deadline
+            .limit(async {
+                sleep(TIMEOUT / 2).await;
+                deadline
+                    .pause_for(TIMEOUT * 10, sleep(TIMEOUT * 5))
+                    .await
+                    .unwrap();
+                sleep(TIMEOUT / 4).await;
+                "done"
+            })
+            .await;
+        assert_eq!(result, Some("done"));
+
+        // the paused time is not given back twice
+        let start = Instant::now();
+        assert_eq!(deadline.limit(pending::<()>()).await, None);
+        assert_eq!(start.elapsed(), TIMEOUT / 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_future_has_its_own_limit() {
+        let deadline = Deadline::after(TIMEOUT);
+
+        let result = deadline.pause_for(TIMEOUT, sleep(TIMEOUT * 2)).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! Helpers for the entities telegram marks in the message text
+
+use grammers_client::types::Message;
+use grammers_tl_types::types::MessageEntityBotCommand;
+
+use crate::bot::BotContext;
+
+/// Text of an entity, given the message text in UTF-16 code units
+pub fn entity_text(text: &[u16], offset: i32, length: i32) -> String {
+    let start = (offset as usize).min(text.len());
+    let end = ((offset + length) as usize).min(text.len());
+    String::from_utf16_lossy(&text[start..end])
+}
+
+/// Splits a command like `/dl@bot` into the command itself and the username of the bot it's addressed to
+pub fn split_bot_command(command: &str) -> (&str, Option<&str>) {
+    match command.split_once('@') {
+        Some((command, username)) => (command, Some(username)),
+        None => (command, None),
+    }
+}
+
+/// Returns true if the username (without the `@`) is the bot's own
+pub fn is_own_username(context: &BotContext, username: &str) -> bool {
+    context
+        .me
+        .username()
+        .map_or(false, |own| own.eq_ignore_ascii_case(username))
+}
+
+/// Returns the username of the bot the command is addressed to, like `bot` for `/help@bot`
+fn command_username(message: &Message, command: &MessageEntityBotCommand) -> Option<String> {
+    let text = message.text().encode_utf16().collect::<Vec<_>>();
+    let command = entity_text(&text, command.offset, command.length);
+    split_bot_command(&command).1.map(str::to_string)
+}
+
+/// Returns the name of the command without the slash and the username, like `help` for `/help@bot`
+pub fn command_name(message: &Message, command: &MessageEntityBotCommand) -> String {
+    let text = message.text().encode_utf16().collect::<Vec<_>>();
+    let command = entity_text(&text, command.offset, command.length);
+    let (name, _) = split_bot_command(&command);
+    name.trim_start_matches('/').to_string()
+}
+
+/// Returns false if the command is addressed to another bot, like `/help@other_bot`
+pub fn is_command_for_bot(
+    message: &Message,
+    command: &MessageEntityBotCommand,
+    context: &BotContext,
+) -> bool {
+    command_username(message, command).map_or(true, |username| is_own_username(context, &username))
+}
+
+/// Returns true if the command is addressed to the bot by its username, like `/help@bot`
+pub fn is_command_with_own_username(
+    message: &Message,
+    command: &MessageEntityBotCommand,
+    context: &BotContext,
+) -> bool {
+    command_username(message, command).map_or(false, |username| is_own_username(context, &username))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entity_text_counts_utf16_code_units() {
+        let text = "👀 /dl@bot".encode_utf16().collect::<Vec<_>>();
+        // the emoji takes two code units
+        assert_eq!(entity_text(&text, 3, 7), "/dl@bot");
+        assert_eq!(entity_text(&text, 3, 100), "/dl@bot");
+    }
+
+    #[test]
+    fn splits_bot_command() {
+        assert_eq!(split_bot_command("/dl"), ("/dl", None));
+        assert_eq!(split_bot_command("/dl@some_bot"), ("/dl", Some("some_bot")));
+    }
+}
@@ -0,0 +1,59 @@
+//! Decides whether a message in a group chat is meant for the bot
+
+use grammers_client::types::Message;
+use grammers_tl_types::enums;
+use snafu::ResultExt;
+
+use crate::{
+    bot::{
+        entities::{entity_text, is_command_with_own_username, is_own_username},
+        links, BotContext,
+    },
+    whatever::Whatever,
+};
+
+fn is_mentioned(message: &Message, context: &BotContext) -> bool {
+    let text = message.text().encode_utf16().collect::<Vec<_>>();
+
+    message
+        .fmt_entities()
+        .into_iter()
+        .flatten()
+        .any(|entity| match entity {
+            enums::MessageEntity::Mention(mention) => is_own_username(
+                context,
+                entity_text(&text, mention.offset, mention.length).trim_start_matches('@'),
+            ),
+            enums::MessageEntity::MentionName(mention) => mention.user_id == context.me.id(),
+            // `/help@bot` is as good as a mention
+            enums::MessageEntity::BotCommand(command) => {
+                is_command_with_own_username(message, command, context)
+            }
+            _ => false,
+        })
+}
+
+async fn is_reply_to_bot(message: &Message, context: &BotContext) -> Result<bool, Whatever> {
+    if message.reply_to_message_id().is_none() {
+        return Ok(false);
+    }
+
+    let reply = message
+        .get_reply()
+        .await
+        .whatever_context("Loading the replied-to message")?;
+
+    Ok(reply
+        .and_then(|reply| reply.sender())
+        .map_or(false, |sender| sender.id() == context.me.id()))
+}
+
+/// Returns true if the bot was mentioned in the message (a command with its username counts too), the message is a reply to the bot or a download command
+pub async fn is_addressed_to_bot(
+    message: &Message,
+    context: &BotContext,
+) -> Result<bool, Whatever> {
+    Ok(is_mentioned(message, context)
+        || links::is_download_command(message, context)
+        || is_reply_to_bot(message, context).await?)
+}
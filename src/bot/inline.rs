@@ -0,0 +1,310 @@
+//! Inline mode: `@bot <url>` in any chat offers the video as an inline result
+//!
+//! Inline results can only reference files that are already on telegram servers,
+//! so the videos are first sent to a staging chat and then re-used from the cache.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use grammers_client::{
+    types::{inline::query::Article, Chat, InlineQuery, Media, Message},
+    InputMessage,
+};
+use grammers_tl_types as tl;
+use snafu::{OptionExt, ResultExt};
+use tracing::{debug, error, info, instrument};
+use url::Url;
+
+use crate::{
+    bot::{
+        deadline::Deadline,
+        lang::Lang,
+        options::OutputMode,
+        remember_user,
+        roles::{self, DownloadsDenied, Role},
+        upload::{self, PreparedVideo},
+        BotContext, UploadNotifier, UserId,
+    },
+    downloader::{DownloadOptions, Downloader},
+    whatever::Whatever,
+};
+
+/// How long the uploaded videos are reused for
+pub const UPLOAD_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Telegram drops the inline query if it is not answered in time, so don't wait for the upload longer than that
+const INLINE_ANSWER_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// A video that is already on telegram servers
+#[derive(Clone)]
+pub struct CachedVideo {
+    document: tl::enums::InputDocument,
+    canonical_url: Url,
+    link_text: &'static str,
+}
+
+pub type StagingUpload = Shared<BoxFuture<'static, Option<CachedVideo>>>;
+
+fn input_document(message: &Message) -> Option<tl::enums::InputDocument> {
+    let Some(Media::Document(document)) = message.media() else {
+        return None;
+    };
+    let tl::enums::Document::Document(document) = document.raw.document.clone()? else {
+        return None;
+    };
+
+    Some(
+        tl::types::InputDocument {
+            id: document.id,
+            access_hash: document.access_hash,
+            file_reference: document.file_reference,
+        }
+        .into(),
+    )
+}
+
+/// Remembers the video sent in `message`, so that it can be offered in the inline mode without downloading it again
+pub fn cache_upload(context: &BotContext, url: Url, video: &PreparedVideo, message: &Message) {
+    let Some(document) = input_document(message) else {
+        error!("Sent video message does not contain a document??");
+        return;
+    };
+
+    let cached = CachedVideo {
+        document,
+        canonical_url: video.canonical_url.clone(),
+        link_text: video.link_text,
+    };
+
+    let mut cache = context.upload_cache.lock().unwrap();
+    cache.insert(video.canonical_url.clone(), cached.clone());
+    cache.insert(url, cached);
+}
+
+async fn staging_chat(context: &BotContext) -> Result<Chat, Whatever> {
+    let config = context
+        .inline
+        .as_ref()
+        .whatever_context("Inline mode is not configured")?;
+
+    context
+        .staging_chat
+        .get_or_try_init(|| async {
+            context
+                .client
+                .resolve_username(&config.staging_chat)
+                .await
+                .whatever_context("Resolving the staging chat")?
+                .whatever_context("Staging chat does not exist")
+        })
+        .await
+        .cloned()
+}
+
+/// Downloads the video and sends it to the staging chat
+async fn upload_to_staging(
+    context: Arc<BotContext>,
+    url: Url,
+    downloader: Arc<dyn Downloader>,
+    options: DownloadOptions,
+) -> Result<(), Whatever> {
+    let chat = staging_chat(&context).await?;
+
+    // nobody is watching the progress, but the receiver must stay alive for the notifications to be accepted
+    let (notifier, _notification_rx) = UploadNotifier::make();
+
+    let deadline = Deadline::after(context.video_handling_timeout);
+    let video = deadline
+        .limit(upload::prepare_video(
+            &context,
+            downloader,
+            url.clone(),
+            options,
+            OutputMode::Video,
+            notifier,
+            &deadline,
+        ))
+        .await
+        .whatever_context("Took too long to upload the video")??;
+
+    let sent_message = upload::send_video_message(&context, chat.pack(), None, &video.message)
+        .await
+        .whatever_context("Sending the video to the staging chat")?
+        .whatever_context("The video was sent as an animation")?;
+
+    cache_upload(&context, url, &video, &sent_message);
+
+    Ok(())
+}
+
+/// Returns the cached video, uploading it if necessary
+///
+/// Concurrent queries for the same url share a single upload, only the user who starts it
+/// needs the role to allow it and has it counted towards their quota.
+/// `Ok(None)` if the upload is not done in time.
+async fn get_or_upload(
+    context: &Arc<BotContext>,
+    url: &Url,
+    user: UserId,
+    role: Role,
+) -> Result<Result<Option<CachedVideo>, DownloadsDenied>, Whatever> {
+    let cached = context.upload_cache.lock().unwrap().get(url).cloned();
+    if let Some(cached) = cached {
+        // nothing is downloaded, but the role still has to allow the site
+        let permissions = roles::permissions(context, role);
+        let allowed = context
+            .dispatcher
+            .downloaders_for(url, None)
+            .any(|downloader| permissions.allows_downloader(downloader.as_ref()));
+        return Ok(if allowed {
+            Ok(Some(cached))
+        } else {
+            Err(DownloadsDenied::Unsupported)
+        });
+    }
+
+    let running = context.staging_uploads.lock().unwrap().get(url).cloned();
+    let upload = match running {
+        Some(upload) => upload,
+        None => {
+            let (downloader, options) = match roles::authorize_downloads(
+                context,
+                user,
+                role,
+                vec![url.clone()],
+                None,
+                DownloadOptions::default(),
+            )
+            .await?
+            {
+                Ok(mut authorized) => (authorized.items.remove(0).1, authorized.options),
+                Err(denied) => return Ok(Err(denied)),
+            };
+
+            let upload = {
+                let context = context.clone();
+                let url = url.clone();
+                async move {
+                    if let Err(e) =
+                        upload_to_staging(context.clone(), url.clone(), downloader, options).await
+                    {
+                        error!(
+                            "Uploading {} to the staging chat failed: {}",
+                            url,
+                            snafu::Report::from_error(e)
+                        );
+                        if let Err(e) = context.download_quotas.refund(user, 1).await {
+                            error!("Could not give back the download of {:?}: {}", user, e);
+                        }
+                    }
+                    context.staging_uploads.lock().unwrap().remove(&url);
+                    context.upload_cache.lock().unwrap().get(&url).cloned()
+                }
+                .boxed()
+                .shared()
+            };
+            let running = {
+                let mut staging_uploads = context.staging_uploads.lock().unwrap();
+                // another query may have started the same upload while the quota was taken
+                let running = staging_uploads.get(&url).cloned();
+                if running.is_none() {
+                    staging_uploads.insert(url.clone(), upload.clone());
+                }
+                running
+            };
+            match running {
+                Some(running) => {
+                    context.download_quotas.refund(user, 1).await?;
+                    running
+                }
+                None => upload,
+            }
+        }
+    };
+
+    // keep the upload running in the background even if the query times out,
+    // so that the next query for this url hits the cache
+    tokio::spawn(upload.clone());
+
+    Ok(Ok(tokio::time::timeout(INLINE_ANSWER_TIMEOUT, upload)
+        .await
+        .ok()
+        .flatten()))
+}
+
+fn article(title: Lang, message: Lang) -> tl::enums::InputBotInlineResult {
+    Article::new(title.to_string(), InputMessage::from(message)).into()
+}
+
+fn video_result(video: CachedVideo) -> tl::enums::InputBotInlineResult {
+    let message = video.link_text.to_string();
+    let entity = tl::types::MessageEntityTextUrl {
+        offset: 0,
+        length: message.encode_utf16().count() as i32,
+        url: video.canonical_url.to_string(),
+    };
+
+    tl::types::InputBotInlineResultDocument {
+        id: video.canonical_url.to_string(),
+        r#type: "video".to_string(),
+        title: Some(Lang::InlineVideoTitle.to_string()),
+        description: Some(video.canonical_url.to_string()),
+        document: video.document,
+        send_message: tl::types::InputBotInlineMessageMediaAuto {
+            invert_media: false,
+            message,
+            entities: Some(vec![entity.into()]),
+            reply_markup: None,
+        }
+        .into(),
+    }
+    .into()
+}
+
+#[instrument(skip_all, fields(sender_id = query.sender().id(), text = query.text()), err(Debug))]
+pub async fn handle_inline_query(
+    query: InlineQuery,
+    context: Arc<BotContext>,
+) -> Result<(), Whatever> {
+    remember_user(&context, query.sender().pack());
+    let sender = UserId(query.sender().id());
+
+    let result = match roles::user_role(&context, sender).await {
+        None => {
+            info!("Ignoring inline query from non-whitelisted user");
+            Some(article(Lang::InlineNoAccessTitle, Lang::InlineNoAccess))
+        }
+        Some(_) if context.inline.is_none() => {
+            Some(article(Lang::InlineDisabledTitle, Lang::InlineDisabled))
+        }
+        Some(role) => match Url::parse(query.text().trim()) {
+            Err(_) => None,
+            Ok(url) => {
+                debug!("Looking up the video for {}", url);
+                match get_or_upload(&context, &url, sender, role).await? {
+                    Ok(Some(video)) => Some(video_result(video)),
+                    Ok(None) => Some(article(Lang::InlineNotReadyTitle, Lang::InlineNotReady)),
+                    Err(DownloadsDenied::Unsupported) => {
+                        Some(article(Lang::InlineUnsupportedTitle, Lang::UnsupportedUrl))
+                    }
+                    Err(DownloadsDenied::QuotaExceeded(limit)) => Some(article(
+                        Lang::InlineQuotaExceededTitle,
+                        Lang::QuotaExceeded(limit),
+                    )),
+                }
+            }
+        },
+    };
+
+    query
+        .answer(result)
+        .cache_time(0)
+        .private()
+        .send()
+        .await
+        .whatever_context("Answering the inline query")?;
+
+    Ok(())
+}
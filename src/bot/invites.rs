@@ -0,0 +1,211 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use super::UserId;
+use crate::{
+    state_store::{Collection, StateStore},
+    whatever::Whatever,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Invite {
+    /// `None` if the invite can be used any number of times
+    pub uses_left: Option<u32>,
+    /// `None` if the invite never expires
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_by: UserId,
+}
+
+impl Invite {
+    fn is_valid(&self, now: DateTime<Utc>) -> bool {
+        self.uses_left != Some(0) && self.expires_at.map_or(true, |expires_at| expires_at > now)
+    }
+}
+
+/// Keeps track of the invite codes storing updates in the state store.
+pub struct Invites {
+    store: Arc<dyn StateStore>,
+    invites: HashMap<String, Invite>,
+}
+
+impl Invites {
+    pub fn new_empty(store: Arc<dyn StateStore>) -> Self {
+        Self {
+            store,
+            invites: Default::default(),
+        }
+    }
+
+    /// Loads state from the store
+    pub async fn load(store: Arc<dyn StateStore>) -> Result<Self, Whatever> {
+        let mut me = Self::new_empty(store);
+        me.invites = me
+            .store
+            .load_as(Collection::Invites)
+            .await
+            .whatever_context("Loading invites")?;
+        Ok(me)
+    }
+
+    async fn persist(&mut self) -> Result<(), Whatever> {
+        // expired and used up invites are of no use to anyone
+        let now = Utc::now();
+        self.invites.retain(|_, invite| invite.is_valid(now));
+
+        self.store
+            .store_as(Collection::Invites, &self.invites)
+            .await
+    }
+
+    /// Creates a new invite, returning its code.
+    ///
+    /// The code only has the characters allowed in the `/start` deep link parameter.
+    pub async fn create(&mut self, invite: Invite) -> Result<String, Whatever> {
+        let code = format!("{:016x}", rand::random::<u64>());
+        self.invites.insert(code.clone(), invite);
+        self.persist().await.whatever_context("Storing state")?;
+        Ok(code)
+    }
+
+    /// Returns the invite if it can be redeemed
+    pub fn get_valid(&self, code: &str) -> Option<&Invite> {
+        self.invites
+            .get(code)
+            .filter(|invite| invite.is_valid(Utc::now()))
+    }
+
+    /// Uses up the invite once.
+    ///
+    /// Returns `None` if there is no such invite, or it's expired or used up.
+    pub async fn redeem(&mut self, code: &str) -> Result<Option<Invite>, Whatever> {
+        let Some(invite) = self.use_once(code, Utc::now()) else {
+            return Ok(None);
+        };
+        self.persist().await.whatever_context("Storing state")?;
+        Ok(Some(invite))
+    }
+
+    fn use_once(&mut self, code: &str, now: DateTime<Utc>) -> Option<Invite> {
+        let invite = self.invites.get_mut(code)?;
+        if !invite.is_valid(now) {
+            return None;
+        }
+        if let Some(uses_left) = &mut invite.uses_left {
+            *uses_left -= 1;
+        }
+        Some(invite.clone())
+    }
+
+    /// Removes the invite.
+    ///
+    /// Returns false if there was no such invite.
+    pub async fn revoke(&mut self, code: &str) -> Result<bool, Whatever> {
+        let removed = self.invites.remove(code);
+        if removed.is_some() {
+            self.persist().await.whatever_context("Storing state")?;
+        }
+        Ok(removed.is_some())
+    }
+
+    /// Returns the invites that can still be redeemed
+    pub fn valid_invites(&self) -> impl Iterator<Item = (&String, &Invite)> {
+        let now = Utc::now();
+        self.invites
+            .iter()
+            .filter(move |(_, invite)| invite.is_valid(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{config, state_store::JsonFileStore};
+
+    fn invite(uses_left: Option<u32>, expires_at: Option<DateTime<Utc>>) -> Invite {
+        Invite {
+            uses_left,
+            expires_at,
+            created_by: UserId(1),
+        }
+    }
+
+    #[test]
+    fn validity() {
+        let now = Utc::now();
+        assert!(invite(None, None).is_valid(now));
+        assert!(invite(Some(1), Some(now + Duration::minutes(1))).is_valid(now));
+        assert!(!invite(Some(0), None).is_valid(now));
+        assert!(!invite(None, Some(now)).is_valid(now));
+        assert!(!invite(None, Some(now - Duration::minutes(1))).is_valid(now));
+    }
+
+    fn store(dir: &TempDir) -> Arc<dyn StateStore> {
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        Arc::new(JsonFileStore::new(&config::Data {
+            whitelist_file: path("whitelist.json"),
+            invites_file: None,
+            state_store: config::StateStoreKind::Json,
+        }))
+    }
+
+    #[test]
+    fn counts_the_uses() {
+        let dir = TempDir::new().unwrap();
+        let now = Utc::now();
+        let mut invites = Invites::new_empty(store(&dir));
+        invites
+            .invites
+            .insert("limited".to_string(), invite(Some(2), None));
+        invites
+            .invites
+            .insert("unlimited".to_string(), invite(None, None));
+
+        assert_eq!(invites.use_once("limited", now).unwrap().uses_left, Some(1));
+        assert_eq!(invites.use_once("limited", now).unwrap().uses_left, Some(0));
+        assert!(invites.use_once("limited", now).is_none());
+        assert!(invites.get_valid("limited").is_none());
+
+        for _ in 0..3 {
+            assert_eq!(invites.use_once("unlimited", now).unwrap().uses_left, None);
+        }
+        assert!(invites.get_valid("unlimited").is_some());
+
+        assert!(invites.use_once("unknown", now).is_none());
+    }
+
+    #[test]
+    fn expired_invite_is_not_used() {
+        let dir = TempDir::new().unwrap();
+        let now = Utc::now();
+        let mut invites = Invites::new_empty(store(&dir));
+        invites.invites.insert(
+            "expired".to_string(),
+            invite(Some(3), Some(now - Duration::hours(1))),
+        );
+
+        assert!(invites.use_once("expired", now).is_none());
+        assert_eq!(invites.invites["expired"].uses_left, Some(3));
+    }
+
+    #[tokio::test]
+    async fn stores_the_redeemed_uses() {
+        let dir = TempDir::new().unwrap();
+        let mut invites = Invites::new_empty(store(&dir));
+        let code = invites.create(invite(Some(2), None)).await.unwrap();
+
+        assert!(invites.redeem(&code).await.unwrap().is_some());
+        let mut invites = Invites::load(store(&dir)).await.unwrap();
+        assert_eq!(invites.get_valid(&code).unwrap().uses_left, Some(1));
+
+        assert!(invites.redeem(&code).await.unwrap().is_some());
+        // the used up invite is dropped from the store
+        let invites = Invites::load(store(&dir)).await.unwrap();
+        assert!(invites.invites.is_empty());
+    }
+}
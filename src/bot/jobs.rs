@@ -0,0 +1,105 @@
+//! Keeps track of the in-flight download jobs, so that they can be cancelled
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio_util::sync::CancellationToken;
+
+use super::UserId;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
+pub struct JobId(pub u64);
+
+impl Display for JobId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for JobId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(JobId)
+    }
+}
+
+struct Job {
+    owner: UserId,
+    cancel: CancellationToken,
+}
+
+pub enum CancelResult {
+    Cancelled,
+    NotAllowed,
+    NotFound,
+}
+
+#[derive(Default)]
+pub struct JobRegistry {
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<JobId, Job>>,
+}
+
+/// Removes the job from the registry when dropped
+pub struct JobGuard {
+    registry: Arc<JobRegistry>,
+    pub id: JobId,
+    pub cancel: CancellationToken,
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.registry.jobs.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl JobRegistry {
+    /// Allocates an id for a job that is not started yet
+    pub fn allocate_id(&self) -> JobId {
+        JobId(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Registers a new job started by `owner`
+    pub fn start(self: &Arc<Self>, owner: UserId) -> JobGuard {
+        let id = self.allocate_id();
+        let cancel = CancellationToken::new();
+
+        self.jobs.lock().unwrap().insert(
+            id,
+            Job {
+                owner,
+                cancel: cancel.clone(),
+            },
+        );
+
+        JobGuard {
+            registry: self.clone(),
+            id,
+            cancel,
+        }
+    }
+
+    /// Cancels the job, if the `requester` is allowed to do it
+    ///
+    /// Only the job owner and the users managers can cancel a job.
+    pub fn cancel(&self, id: JobId, requester: UserId, is_manager: bool) -> CancelResult {
+        let jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get(&id) else {
+            return CancelResult::NotFound;
+        };
+        if job.owner != requester && !is_manager {
+            return CancelResult::NotAllowed;
+        }
+
+        job.cancel.cancel();
+        CancelResult::Cancelled
+    }
+}
@@ -10,11 +10,21 @@ pub enum Lang {
     NoUrl,
     /// I donbt no ho to doload tis url((999
     UnsupportedUrl,
+    /// I donbt no ho to doload tese urls((999
+    UnsupportedUrls,
 
     /// Wowking~   (ﾉ>ω<)ﾉ
     StatusWorking,
     /// Gettinb vid linkie (；⌣̀_⌣́)～
     StatusGettingLink,
+    /// Twanscoding so it pways evewywhewe~ (ง •̀_•́)ง
+    StatusTranscoding,
+    /// Squishing it to fit~ (っ˘ω˘ς)
+    StatusCompressing,
+    /// Done ✓
+    StatusItemDone,
+    /// Faiwed ✗
+    StatusItemFailed,
 
     /// did it!1!1!  (ﾉ>ω<)ﾉ :｡･:*:･ﾟ’★,｡･:*:･ﾟ’☆
     ResultSuccess,
@@ -25,17 +35,108 @@ pub enum Lang {
 
     {0}*/
     ResultGenericError(String),
+    /// cancewwed (・へ・)
+    ResultCancelled,
+    /**
+    {0}. {1}
+    {2}*/
+    ResultItem(usize, String, String),
+    /**
+    I donbt no ho to doload tese ((999
+
+    {0}*/
+    ResultUnsupportedList(String),
+    /// \[Skipped {0} more links, I only take {1} at a time\]
+    ResultSkippedUrls(usize, usize),
+
+    /// Cancel ✋
+    ButtonCancel,
+    /// Retry 🔁
+    ButtonRetry,
+    /// Try another downloader 🔀
+    ButtonNextDownloader,
+    /// Retry #{0} 🔁
+    ButtonRetryItem(usize),
+    /// Try another downloader for #{0} 🔀
+    ButtonNextDownloaderItem(usize),
+    /// Wequest access 🙏
+    ButtonRequestAccess,
+    /// Appwove ✅
+    ButtonApprove,
+    /// Deny ❌
+    ButtonDeny,
+    /// Stoppinb it~
+    CallbackCancelling,
+    /// Tis is not youw video (¬_¬)
+    CallbackNotAllowed,
+    /// Tis button does nothing anymowe
+    CallbackExpired,
+    /// Twying again~ (ง •̀_•́)ง
+    CallbackRetrying,
+    /// Gud choice~
+    CallbackPicked,
+
+    /// Hewe's youw vid 🎬
+    InlineVideoTitle,
+    /// Access wequiwed 🔒
+    InlineNoAccessTitle,
+    /// sowwy i am not awwowed to spek with pepel i donbt now (yet) (/ω＼)\nAsk the bot owner for access~
+    InlineNoAccess,
+    /// Inline mode is off 💤
+    InlineDisabledTitle,
+    /// Inline mode is not set up for tis bot (／ω＼)
+    InlineDisabled,
+    /// Unsuppowted link 🤔
+    InlineUnsupportedTitle,
+    /// Stiww downwoading~ twy again in a moment ⏳
+    InlineNotReadyTitle,
+    /// The video is stiww being pwepawed, twy again in a moment (ﾉ>ω<)ﾉ
+    InlineNotReady,
+    /// No mowe downwoads today 🌙
+    InlineQuotaExceededTitle,
+
+    /// 🗜 compressed to fit the size limit
+    CaptionCompressed,
+    /// Subtitwes ({0}) 📝
+    SubtitlesCaption(String),
+    /// ⭕ too long for a wound video, sent as a weguwaw one
+    CaptionRoundTooLong,
+    /// ⭕ donbt no how wong da video is, sent as a weguwaw one
+    CaptionRoundUnknownDuration,
+
+    /// Videos in tis chat awe sent as {0} (ﾉ>ω<)ﾉ \[/mode video|round|gif to change\]
+    OutputModeCurrent(&'static str),
+    /// Okie, videos in tis chat wiww be sent as {0} ✨
+    OutputModeSet(&'static str),
+    /// I donbt no tis mode ☆⌒(> _ <) \[video, round ow gif\]
+    OutputModeUnknown,
+    /// Onwy da admins can change da mode in gwoups (／ω＼)
+    OutputModeNotAllowed,
+
+    /// Pick da quality (｡•̀ᴗ-)✧
+    PickFormat,
+    /// Picked {0} ✨
+    PickFormatChosen(String),
 
     /// I donbt understan tis command ☆⌒(> _ <) \[/help might help\]
     CommandUnknown,
     /// Tis command needs args ☆⌒(> _ <) \[/help might help\]
     CommandNeedsArgs,
+    /// Tis command is not fow you (／ω＼)
+    CommandNotAllowed,
     /// Tis command needs different args ☆⌒(> _ <) \[/help might help\]
     CommandIncorrectArgs,
     /**
     /whitelist - show users in whitelist
-    /whitelist_add @username - add user to the whitelist
-    /whitelist_remove @username - remove user from the whitelist
+    /whitelist_add @username|id [7d] - add user to the whitelist, optionally for a limited time
+    /whitelist_remove @username|id - remove user from the whitelist
+    (reply to a message forwarded from the user or to their contact instead of naming them)
+    /invite [uses] [7d] - create an invite link, optionally limited by uses and time
+    /invites - show the invites that can still be used
+    /invite_revoke code - revoke an invite
+    /role @username|id admin|power|user|guest - change the role of a whitelisted user
+    /dl - reply to a message to download the links in it
+    /mode video|round|gif - how the videos are sent in this chat (mode:round next to a link for just that one)
     /help - show this message*/
     CommandHelp,
 
@@ -43,19 +144,80 @@ pub enum Lang {
     WhitelistErrorAliasResolve,
     /// Cannot access info of {0}
     WhitelistErrorNoAccessHash(i64),
+    /// I can't see who dat is 🔍\nReply to a message forwarded from them (unless they hide their forwards) or to their contact
+    WhitelistErrorNoUserInReply,
     /// Added the user successfully! ✨ Now they can use this bot ✨
     WhitelistAddOk,
+    /// Added the user successfully! ✨ Now they can use this bot until {0} ✨
+    WhitelistAddOkUntil(String),
     /// ✨ I already know this person! (or bot 🤔) ✨
     WhitelistAddKnown,
+    /// ✨ I already know this person! ✨ They can stay until {0} now
+    WhitelistAddKnownUntil(String),
     /// Removed the user successfully.. We're not friends anymore 😭😭😭
     WhitelistRemoveOk,
     /// Who's dat? Idk them, do you? 👊🤨
     WhitelistRemoveUnknown,
 
+    /**
+    Hewe's da invite link~ ✨ Send it to youw fwiend
+
+    {0}*/
+    InviteCreated(String),
+    /// Revoked da invite 🚫
+    InviteRevokeOk,
+    /// I donbt no tis invite 🤔
+    InviteRevokeUnknown,
+    /// Invites dat stiww wowk 💌
+    InviteListHead,
+    /// {0} - uses weft: {1}, expiwes: {2}
+    InviteListItem(String, String, String),
+    /// Yay, we'we fwiends now~ ✨ Sen me a link to a video (ﾉ>ω<)ﾉ
+    InviteRedeemed,
+    /// Tis invite is not vawid (anymowe) (/ω＼)
+    InviteInvalid,
+    /// ✨ I already know you! ✨ Just sen me a link~
+    InviteAlreadyAllowed,
+
+    /// Asked da supewusews fow you~ 💌
+    AccessRequestSent,
+    /// Youw wequest is stiww waiting, be patient (｡•́︿•̀｡)
+    AccessRequestPending,
+    /// Couwdn't weach anyone to ask (／ω＼) twy again watew
+    AccessRequestFailed,
+    /**
+    Someone wants to be fwiends 👀
+
+    Name: {0}
+    Usewname: {1}
+    ID: {2}*/
+    AccessRequestNotification(String, String, String),
+    /// Appwoved ✅
+    AccessRequestApproved,
+    /// Denied ❌
+    AccessRequestDenied,
+    /// Someone already decided on tis one 🤷
+    AccessRequestAlreadyDecided,
+    /// Yay, youw access was appwoved~ ✨ Sen me a link to a video (ﾉ>ω<)ﾉ
+    AccessRequestApprovedNotification,
+
+    /// Changed da wole to {0} ✨
+    RoleSetOk(&'static str),
+    /// I donbt no tis pewson yet (／ω＼) add dem to da whitelist befowe giving dem a wowe
+    RoleSetUnknown,
+    /// Supewusews fwom da config awe awways admins 👑
+    RoleSuperuser,
+    /// You've used up youw {0} downwoads fow today (｡•́︿•̀｡) come back tomowwow~
+    QuotaExceeded(u32),
+
     /// List of my absolute besties 👯‍🌸️😎
     WhitelistListHead,
     /// beeestieee {0} 😎 (the best one!!!)
     WhitelistListItem(String),
+    /// temporawy bestie {0} ⏳ (until {1})
+    WhitelistListItemUntil(String, String),
+    /// Youw access to tis bot has expiwed (｡•́︿•̀｡) it was nice knowing you
+    WhitelistExpired,
 }
 
 impl From<Lang> for grammers_client::InputMessage {
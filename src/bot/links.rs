@@ -0,0 +1,105 @@
+//! Finds the links a message asks the bot to download
+//!
+//! Forwarded posts carry their own text and entities, so they need no special handling.
+//! For media messages, [Message::text] is the caption.
+
+use grammers_client::types::Message;
+use grammers_tl_types as tl;
+use snafu::ResultExt;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{
+    bot::{
+        entities::{entity_text, is_own_username, split_bot_command},
+        BotContext,
+    },
+    whatever::Whatever,
+};
+
+/// Replying with this command to a message downloads the links from it
+const DOWNLOAD_COMMAND: &str = "/dl";
+
+/// Adds the url to the list, unless it's already there
+fn push_url(urls: &mut Vec<Url>, url: &str) {
+    // telegram marks links without the scheme too (like `youtu.be/...`)
+    let parsed = match Url::parse(url) {
+        Err(url::ParseError::RelativeUrlWithoutBase) => Url::parse(&format!("https://{}", url)),
+        parsed => parsed,
+    };
+
+    match parsed {
+        Ok(parsed) if !urls.contains(&parsed) => urls.push(parsed),
+        Ok(_) => debug!("Skipping duplicate url {}", url),
+        Err(e) => warn!(
+            "Telegram marked {:?} as a url, but it's not one: {}",
+            url, e
+        ),
+    }
+}
+
+/// Collects the links from the text, the hyperlinks and the web page preview of the message
+fn collect_message_urls(message: &Message, urls: &mut Vec<Url>) {
+    let text = message.text().encode_utf16().collect::<Vec<_>>();
+
+    for entity in message.fmt_entities().into_iter().flatten() {
+        match entity {
+            tl::enums::MessageEntity::Url(url) => {
+                push_url(urls, &entity_text(&text, url.offset, url.length))
+            }
+            tl::enums::MessageEntity::TextUrl(url) => push_url(urls, &url.url),
+            _ => {}
+        }
+    }
+
+    // the preview usually repeats one of the links, but the link itself may be gone from the text
+    if let Some(tl::enums::MessageMedia::WebPage(media)) = &message.raw.media {
+        if let tl::enums::WebPage::Page(page) = &media.webpage {
+            push_url(urls, &page.url);
+        }
+    }
+}
+
+/// Returns true if the message starts with the download command
+///
+/// The command addressed to another bot (like `/dl@other_bot`) is not ours to handle.
+pub fn is_download_command(message: &Message, context: &BotContext) -> bool {
+    let text = message.text().encode_utf16().collect::<Vec<_>>();
+
+    message
+        .fmt_entities()
+        .into_iter()
+        .flatten()
+        .any(|entity| match entity {
+            tl::enums::MessageEntity::BotCommand(command) if command.offset == 0 => {
+                let command = entity_text(&text, command.offset, command.length);
+                match split_bot_command(&command) {
+                    (DOWNLOAD_COMMAND, None) => true,
+                    (DOWNLOAD_COMMAND, Some(username)) => is_own_username(context, username),
+                    _ => false,
+                }
+            }
+            _ => false,
+        })
+}
+
+/// Collects the distinct links of the message, in the order they appear in
+///
+/// If the message is the download command sent as a reply, the links of the replied-to message are included too.
+pub async fn find_urls(message: &Message, context: &BotContext) -> Result<Vec<Url>, Whatever> {
+    let mut urls = Vec::new();
+    collect_message_urls(message, &mut urls);
+
+    if is_download_command(message, context) && message.reply_to_message_id().is_some() {
+        let reply = message
+            .get_reply()
+            .await
+            .whatever_context("Loading the replied-to message")?;
+        match reply {
+            Some(reply) => collect_message_urls(&reply, &mut urls),
+            None => debug!("The replied-to message is gone"),
+        }
+    }
+
+    Ok(urls)
+}
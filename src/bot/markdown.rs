@@ -147,6 +147,45 @@ pub fn escape_code(s: &str) -> String {
     s.replace('\\', r"\\").replace('`', r"\`")
 }
 
+/// Length of the text as telegram shows it, in UTF-16 code units.
+///
+/// The style markers, the escaping backslashes and the link urls are not counted.
+/// Code spans are not treated specially, so the markers inside them are left out too.
+pub fn visible_length(s: &str) -> usize {
+    let mut length = 0;
+    let mut chars = s.chars().peekable();
+    let mut in_link_url = false;
+    let mut line_start = true;
+
+    while let Some(c) = chars.next() {
+        let at_line_start = std::mem::replace(&mut line_start, c == '\n');
+        if in_link_url {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                ')' => in_link_url = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '\\' => length += chars.next().map_or(0, char::len_utf16),
+            '*' | '_' | '~' | '|' | '`' | '[' => {}
+            ']' if chars.peek() == Some(&'(') => {
+                chars.next();
+                in_link_url = true;
+            }
+            // a quote block
+            '>' if at_line_start => {}
+            c => length += c.len_utf16(),
+        }
+    }
+
+    length
+}
+
 // #[must_use = "This function returns a new string, rather than mutating the argument, so calling it \
 //               without using its output does nothing useful"]
 // pub fn user_mention_or_link(user: &User) -> String {
@@ -155,3 +194,23 @@ pub fn escape_code(s: &str) -> String {
 //         None => link(user.url().as_str(), &escape(&user.full_name())),
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn visible_length_skips_markup() {
+        assert_eq!(visible_length("plain text"), 10);
+        assert_eq!(visible_length(&bold(&italic("styled"))), 6);
+        assert_eq!(visible_length(&escape("1.5 (ok)!")), 9);
+        assert_eq!(
+            visible_length(&link("https://example.com/a_(b)", "link")),
+            4
+        );
+        assert_eq!(visible_length(&user_mention(UserId(1), "name")), 4);
+        assert_eq!(visible_length(">quote\n2 > 1"), 11);
+        // the emoji takes two UTF-16 code units
+        assert_eq!(visible_length("👀"), 2);
+    }
+}
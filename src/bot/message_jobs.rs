@@ -0,0 +1,91 @@
+//! Remembers the links of the recent messages and the jobs started for them,
+//! so that editing a message can start jobs for the new links and cancel the ones for the removed links
+
+use std::{collections::HashMap, time::Duration};
+
+use grammers_client::types::Message;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+use url::Url;
+
+use crate::bot::BotContext;
+
+/// Edits of the messages older than this are ignored
+pub const MESSAGE_JOBS_TTL: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
+pub struct MessageKey {
+    chat_id: i64,
+    message_id: i32,
+}
+
+impl MessageKey {
+    fn of(message: &Message) -> Self {
+        Self {
+            chat_id: message.chat().id(),
+            message_id: message.id(),
+        }
+    }
+}
+
+/// The links of a message, as of its last version
+#[derive(Default)]
+pub struct MessageLinks {
+    urls: Vec<Url>,
+    /// Cancellation of the items started for the links, finished ones are kept too (cancelling them does nothing)
+    jobs: HashMap<Url, CancellationToken>,
+}
+
+/// Remembers the links of a new message
+///
+/// The messages without links are remembered too, so that links added by editing them are picked up.
+pub fn remember_links(context: &BotContext, message: &Message, urls: &[Url]) {
+    context.message_jobs.lock().unwrap().insert(
+        MessageKey::of(message),
+        MessageLinks {
+            urls: urls.to_vec(),
+            jobs: HashMap::new(),
+        },
+    );
+}
+
+/// Remembers the item started for one of the links of the message
+pub fn register_job(context: &BotContext, message: &Message, url: &Url, cancel: CancellationToken) {
+    if let Some(links) = context
+        .message_jobs
+        .lock()
+        .unwrap()
+        .get_mut(&MessageKey::of(message))
+    {
+        links.jobs.insert(url.clone(), cancel);
+    }
+}
+
+/// Updates the links of an edited message
+///
+/// Cancels the jobs of the links that are no longer in the message and returns the links that were added.
+/// Returns `None` if the message is not known (too old, or it was not handled at all).
+pub fn update_links(context: &BotContext, message: &Message, urls: &[Url]) -> Option<Vec<Url>> {
+    let mut message_jobs = context.message_jobs.lock().unwrap();
+    let links = message_jobs.get_mut(&MessageKey::of(message))?;
+
+    for (url, cancel) in &links.jobs {
+        if !urls.contains(url) {
+            debug!(
+                "Link {} was removed from the message, cancelling its job",
+                url
+            );
+            cancel.cancel();
+        }
+    }
+    links.jobs.retain(|url, _| urls.contains(url));
+
+    let added = urls
+        .iter()
+        .filter(|url| !links.urls.contains(url))
+        .cloned()
+        .collect();
+    links.urls = urls.to_vec();
+
+    Some(added)
+}
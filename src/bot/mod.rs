@@ -1,75 +1,292 @@
+mod access_groups;
+mod access_requests;
+mod callback;
+mod caption;
 mod commands;
+mod deadline;
+mod entities;
+mod groups;
+mod inline;
+pub mod invites;
+mod jobs;
 mod lang;
+mod links;
 mod markdown;
+mod message_jobs;
+mod options;
+mod picker;
+mod resumable;
+pub mod roles;
+mod scheduler;
 mod upload;
 pub mod whitelist;
 
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use grammers_client::{
-    types::{Chat, Message},
+    types::{Chat, Message, User},
     Client, InputMessage, Update,
 };
+use grammers_session::{PackedChat, PackedType};
 use grammers_tl_types::enums;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt as _;
 use tokio::sync::Mutex;
 use tracing::{debug, error, info, instrument, warn};
-use upload::UploadError;
+use upload::{JobItem, UploadError};
 use url::Url;
 
 pub use self::upload::{UploadNotifier, UploadStatus};
 use crate::{
-    bot::{commands::handle_command, lang::Lang},
+    bot::{
+        access_groups::ACCESS_GROUP_CACHE_TTL,
+        access_requests::{AccessRequest, ACCESS_REQUEST_TTL},
+        callback::handle_callback_query,
+        commands::handle_command,
+        inline::{handle_inline_query, CachedVideo, StagingUpload, UPLOAD_CACHE_TTL},
+        jobs::{JobId, JobRegistry},
+        lang::Lang,
+        message_jobs::{MessageKey, MessageLinks, MESSAGE_JOBS_TTL},
+        options::{MessageOptions, OutputMode},
+        picker::{PendingChoice, PENDING_CHOICE_TTL},
+        scheduler::{RequestKind, Scheduler},
+    },
+    config,
     dispatcher::DownloadDispatcher,
+    downloader::DownloadOptions,
+    expiring_map::ExpiringMap,
+    state_store::{Collection, StateStore},
     whatever::Whatever,
 };
 
+/// How long the failed jobs can be retried for
+const FAILED_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+/// Links over this number in a single message are ignored
+const MAX_URLS_PER_MESSAGE: usize = 5;
+/// How long the access hashes of the users who wrote to the bot are remembered
+const KNOWN_USERS_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How often the expired whitelist entries are pruned, if enabled
+const PRUNE_EXPIRED_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Ord, PartialOrd, Copy, Clone, Hash)]
 pub struct UserId(pub i64);
 
+/// State shared between all the update handlers
+pub struct BotContext {
+    pub client: Client,
+    pub dispatcher: Arc<DownloadDispatcher>,
+    /// Limit for a single video, not counting the re-encoding
+    pub video_handling_timeout: Duration,
+    /// Seconds the re-encoding may take per second of the video
+    pub encoding_timeout_factor: f64,
+    pub whitelist: Arc<Mutex<whitelist::Whitelist>>,
+    pub invites: Arc<Mutex<invites::Invites>>,
+    /// Always have the [roles::Role::Admin] role
+    pub superusers: HashSet<UserId>,
+    pub roles: HashMap<roles::Role, roles::Permissions>,
+    pub download_quotas: roles::DownloadQuotas,
+    /// Access hashes of the superusers that wrote to the bot, to send them the access requests
+    pub superuser_chats: Mutex<HashMap<UserId, i64>>,
+    pub access_requests: std::sync::Mutex<ExpiringMap<UserId, AccessRequest>>,
+    /// Access hashes of the users who wrote to the bot recently, to add them to the whitelist by id
+    pub known_users: std::sync::Mutex<ExpiringMap<UserId, i64>>,
+    pub access_groups: Vec<config::AccessGroup>,
+    /// Resolved [Self::access_groups], by their index
+    pub access_group_chats: Mutex<HashMap<usize, PackedChat>>,
+    /// Roles the users got from the access groups, `None` for the ones who are not members
+    pub access_group_roles: std::sync::Mutex<ExpiringMap<UserId, Option<roles::Role>>>,
+    /// Group chats the bot works in, by chat id
+    pub groups: HashMap<i64, config::GroupPolicy>,
+    /// The bot's own account
+    pub me: User,
+    pub scheduler: Scheduler,
+    pub jobs: Arc<JobRegistry>,
+    pub failed_jobs: std::sync::Mutex<ExpiringMap<JobId, FailedJob>>,
+    pub pending_choices: std::sync::Mutex<ExpiringMap<JobId, PendingChoice>>,
+    pub inline: Option<config::Inline>,
+    pub captions: config::Captions,
+    pub transcoding: Option<config::Transcoding>,
+    pub upload_size_limit: u64,
+    pub compress_oversized: bool,
+    pub staging_chat: tokio::sync::OnceCell<Chat>,
+    pub upload_cache: std::sync::Mutex<ExpiringMap<Url, CachedVideo>>,
+    pub staging_uploads: std::sync::Mutex<HashMap<Url, StagingUpload>>,
+    pub message_jobs: std::sync::Mutex<ExpiringMap<MessageKey, MessageLinks>>,
+    /// Output modes set with `/mode`, by chat id
+    pub chat_output_modes: Mutex<HashMap<i64, OutputMode>>,
+    pub state_store: Arc<dyn StateStore>,
+}
+
 pub async fn run_bot(
     client: &Client,
     dispatcher: Arc<DownloadDispatcher>,
     video_handling_timeout: Duration,
     whitelist: Arc<Mutex<whitelist::Whitelist>>,
-    superusers: HashSet<UserId>,
+    invites: Arc<Mutex<invites::Invites>>,
+    state_store: Arc<dyn StateStore>,
+    config: config::Config,
 ) -> Result<(), Whatever> {
-    let superusers = Arc::new(superusers);
+    let config::Config {
+        access,
+        inline,
+        captions,
+        transcoding,
+        upload_size_limit,
+        compress_oversized,
+        encoding_timeout_factor,
+        ..
+    } = config;
+
+    let prune_expired_users = access.prune_expired_users;
+
+    let me = client
+        .get_me()
+        .await
+        .whatever_context("Getting the bot's own account")?;
+
+    let chat_output_modes = state_store
+        .load_as(Collection::ChatOutputModes)
+        .await
+        .whatever_context("Loading the chat output modes")?;
+    let download_quotas = roles::DownloadQuotas::load(state_store.clone())
+        .await
+        .whatever_context("Loading the download quotas")?;
+    let superuser_chats = state_store
+        .load_as(Collection::SuperuserChats)
+        .await
+        .whatever_context("Loading the superuser chats")?;
+
+    let context = Arc::new(BotContext {
+        client: client.clone(),
+        dispatcher,
+        video_handling_timeout,
+        encoding_timeout_factor,
+        whitelist,
+        invites,
+        superusers: access.superusers,
+        roles: roles::resolve_permissions(access.roles),
+        download_quotas,
+        superuser_chats: Mutex::new(superuser_chats),
+        access_requests: std::sync::Mutex::new(ExpiringMap::new(ACCESS_REQUEST_TTL)),
+        known_users: std::sync::Mutex::new(ExpiringMap::new(KNOWN_USERS_TTL)),
+        access_groups: access.access_groups,
+        access_group_chats: Default::default(),
+        access_group_roles: std::sync::Mutex::new(ExpiringMap::new(ACCESS_GROUP_CACHE_TTL)),
+        groups: access
+            .groups
+            .into_iter()
+            .map(|policy| (policy.chat_id, policy))
+            .collect(),
+        me,
+        scheduler: Scheduler::new(),
+        jobs: Default::default(),
+        failed_jobs: std::sync::Mutex::new(ExpiringMap::new(FAILED_JOB_TTL)),
+        pending_choices: std::sync::Mutex::new(ExpiringMap::new(PENDING_CHOICE_TTL)),
+        inline,
+        captions,
+        transcoding,
+        upload_size_limit,
+        compress_oversized,
+        staging_chat: Default::default(),
+        upload_cache: std::sync::Mutex::new(ExpiringMap::new(UPLOAD_CACHE_TTL)),
+        staging_uploads: Default::default(),
+        message_jobs: std::sync::Mutex::new(ExpiringMap::new(MESSAGE_JOBS_TTL)),
+        chat_output_modes: Mutex::new(chat_output_modes),
+        state_store,
+    });
+
+    if prune_expired_users {
+        tokio::spawn(prune_expired_users_periodically(context.clone()));
+    }
+
     while let Some(update) = client
         .next_update()
         .await
         .whatever_context("Getting next update")?
     {
-        let Update::NewMessage(message) = update else {
-            continue;
-        };
-        if message.outgoing() {
-            continue;
-        }
-
-        let dispatcher = dispatcher.clone();
-        let client = client.clone();
-        let whitelist = whitelist.clone();
-        let superusers = superusers.clone();
-        tokio::spawn(async move {
-            // error are logged by tracing instrument macro
-            let _ = handle_message(
-                message,
-                client,
-                dispatcher,
-                whitelist,
-                superusers,
-                video_handling_timeout,
-            )
-            .await;
-        });
+        let context = context.clone();
+        match update {
+            Update::NewMessage(message) if !message.outgoing() => {
+                tokio::spawn(async move {
+                    // error are logged by tracing instrument macro
+                    let _ = handle_message(message, context, false).await;
+                });
+            }
+            Update::MessageEdited(message) if !message.outgoing() => {
+                tokio::spawn(async move {
+                    // error are logged by tracing instrument macro
+                    let _ = handle_message(message, context, true).await;
+                });
+            }
+            Update::CallbackQuery(query) => {
+                tokio::spawn(async move {
+                    // error are logged by tracing instrument macro
+                    let _ = handle_callback_query(query, context).await;
+                });
+            }
+            Update::InlineQuery(query) => {
+                tokio::spawn(async move {
+                    // error are logged by tracing instrument macro
+                    let _ = handle_inline_query(query, context).await;
+                });
+            }
+            _ => {}
+        }
     }
 
     info!("Stopped getting updates!");
     Ok(())
 }
 
+/// Removes the expired whitelist entries and tells the users they lost access
+async fn prune_expired_users_periodically(context: Arc<BotContext>) {
+    loop {
+        tokio::time::sleep(PRUNE_EXPIRED_INTERVAL).await;
+
+        let expired = match context.whitelist.lock().await.prune_expired().await {
+            Ok(expired) => expired,
+            Err(e) => {
+                error!("Pruning the expired whitelist entries has failed: {}", e);
+                continue;
+            }
+        };
+
+        for (user, info) in expired {
+            info!("Access of {:?} has expired", user);
+            let chat = PackedChat {
+                ty: PackedType::User,
+                id: user.0,
+                access_hash: Some(info.access_hash),
+            };
+            let message = InputMessage::from(Lang::WhitelistExpired);
+            if let Err(e) = context
+                .scheduler
+                .run(Some(user.0), RequestKind::Required, || {
+                    context.client.send_message(chat, message.clone())
+                })
+                .await
+            {
+                warn!("Could not tell {:?} that their access expired: {}", user, e);
+            }
+        }
+    }
+}
+
+/// Remembers the access hash of the user, to add them to the whitelist or check the access groups by id
+fn remember_user(context: &BotContext, user: PackedChat) {
+    if let Some(access_hash) = user.access_hash {
+        context
+            .known_users
+            .lock()
+            .unwrap()
+            .insert(UserId(user.id), access_hash);
+    }
+}
+
 fn find_message_entity<E, F>(message: &Message, finder: F) -> Option<&E>
 where
     F: for<'a> FnMut(&'a enums::MessageEntity) -> Option<&'a E>,
@@ -90,28 +307,103 @@ fn reply(message: impl Into<InputMessage>) -> Result<MessageResult, Whatever> {
     Ok(MessageResult::Reply(message.into()))
 }
 
-#[instrument(skip_all, fields(chat_id = message.chat().id(), username = message.chat().username()))]
+/// `edited` messages only start jobs for the links that were not in the message before
+#[instrument(skip_all, fields(chat_id = message.chat().id(), username = message.chat().username(), edited))]
 async fn handle_message_impl(
     message: &Message,
-    client: Client,
-    dispatcher: Arc<DownloadDispatcher>,
-    whitelist: Arc<Mutex<whitelist::Whitelist>>,
-    superusers: Arc<HashSet<UserId>>,
-    video_handling_timeout: Duration,
+    context: &BotContext,
+    edited: bool,
 ) -> Result<MessageResult, Whatever> {
     let chat = message.chat();
     debug!("Got message from {:?}", chat.id());
-    if !matches!(chat, Chat::User(_)) {
-        info!("Ignoring message not from private chat ({:?})", chat);
+
+    // in private chats, the sender is the chat itself
+    let Some(sender) = message.sender() else {
+        info!("Ignoring message without a sender");
+        return Ok(MessageResult::Ignore);
+    };
+    if let Chat::User(_) = sender {
+        remember_user(context, sender.pack());
     }
+    let sender = UserId(sender.id());
 
-    if !superusers.contains(&UserId(chat.id()))
-        && !whitelist.lock().await.contains(&UserId(chat.id()))
+    // `None` for private chats
+    let group_policy = match chat {
+        Chat::User(_) => None,
+        _ => match context.groups.get(&chat.id()) {
+            Some(policy) => Some(policy),
+            None => {
+                info!(
+                    "Ignoring message from a group that is not allowed ({:?})",
+                    chat
+                );
+                return Ok(MessageResult::Ignore);
+            }
+        },
+    };
+
+    // in private chats, all the messages are meant for the bot
+    let addressed = match group_policy {
+        None => true,
+        Some(_) => groups::is_addressed_to_bot(message, context).await?,
+    };
+    // in groups, stay silent unless explicitly asked for something
+    let reply_if_addressed = |message: Lang| {
+        if addressed {
+            reply(message)
+        } else {
+            Ok(MessageResult::Ignore)
+        }
+    };
+
+    if !addressed
+        && group_policy.map_or(false, |policy| {
+            policy.trigger == config::GroupTrigger::Mention
+        })
     {
-        info!("Ignoring message from non-superuser ({:?})", chat);
+        debug!("Ignoring message not addressed to the bot");
+        return Ok(MessageResult::Ignore);
+    }
 
-        return reply(Lang::NoAccess);
+    // editing a command should not run it again, and the commands of the other bots are none of our business
+    let command = find_message_entity(message, |e| match e {
+        enums::MessageEntity::BotCommand(command) => Some(command),
+        _ => None,
+    })
+    .filter(|_| !edited)
+    .filter(|command| entities::is_command_for_bot(message, command, context));
+
+    // invites are redeemed before the access check, that's the whole point of them
+    if let (None, Some(command)) = (group_policy, command) {
+        if let Some(response) = commands::handle_start_command(context, command, message).await? {
+            return reply(response);
+        }
+    }
+
+    if let (true, None, Some(access_hash)) = (
+        context.superusers.contains(&sender),
+        group_policy,
+        chat.pack().access_hash,
+    ) {
+        if let Err(e) = access_requests::remember_superuser(context, sender, access_hash).await {
+            warn!("Could not remember the chat of {:?}: {}", sender, e);
+        }
     }
+    let role = match roles::user_role(context, sender).await {
+        Some(role) => role,
+        None if group_policy.map_or(false, |policy| policy.allow_all_members) => roles::Role::Guest,
+        None => {
+            info!("Ignoring message from non-whitelisted user ({:?})", sender);
+
+            if !addressed {
+                return Ok(MessageResult::Ignore);
+            }
+            return reply(
+                InputMessage::from(Lang::NoAccess).reply_markup(&access_requests::request_markup()),
+            );
+        }
+    };
+    let permissions = roles::permissions(context, role);
 
     // if !message
     //     .media()
@@ -125,103 +417,329 @@ async fn handle_message_impl(
     let text = message.text();
     debug!("Text Message: {:#?}", text);
 
-    let text = text.encode_utf16().collect::<Vec<_>>();
+    let options = if permissions.extra_options {
+        MessageOptions::parse(text)
+    } else {
+        MessageOptions::default()
+    };
 
-    // commands are only for superusers
-    if superusers.contains(&UserId(chat.id())) {
-        if let Some(command) = find_message_entity(&message, |e| match e {
-            enums::MessageEntity::BotCommand(command) => Some(command),
-            _ => None,
-        }) {
+    if let Some(command) = command {
+        if !permissions.allows_command(&entities::command_name(message, command)) {
+            info!("{:?} is not allowed to use the command", role);
+            return reply(Lang::CommandNotAllowed);
+        }
+        if let Some(response) =
+            commands::handle_user_command(context, permissions, command, message).await?
+        {
+            return reply(response);
+        }
+    }
+
+    // the rest of the commands are only for the users managers, except for the one everyone can use to download
+    if permissions.manage_users && !links::is_download_command(message, context) {
+        if let Some(command) = command {
             debug!("Found command");
-            return reply(handle_command(&client, command, &message, whitelist).await?);
+            return reply(handle_command(context, command, message).await?);
         } else {
             debug!("No commands were found");
         };
     }
 
-    let Some(url) = find_message_entity(&message, |e| match e {
-        enums::MessageEntity::Url(url) => Some(url),
-        _ => None,
-    }) else {
-        return reply(Lang::NoUrl);
-    };
+    let mut urls = links::find_urls(message, context).await?;
+    if edited {
+        let Some(added) = message_jobs::update_links(context, message, &urls) else {
+            debug!("Ignoring edit of an unknown message");
+            return Ok(MessageResult::Ignore);
+        };
+        if added.is_empty() {
+            debug!("No new links in the edited message");
+            return Ok(MessageResult::Ignore);
+        }
+        urls = added;
+    } else {
+        message_jobs::remember_links(context, message, &urls);
+    }
+    if urls.is_empty() {
+        return reply_if_addressed(Lang::NoUrl);
+    }
+    debug!("Extracted URLs: {:?}", urls);
 
-    // extract the url entity text
-    let url = &text[url.offset as usize..(url.offset + url.length) as usize];
-    let url = String::from_utf16(url).whatever_context("Parsing Url codepoints as string")?;
-    let url = Url::parse(&url).whatever_context("Parsing Url that telegram marked as a Url")?;
+    let skipped = urls.len().saturating_sub(MAX_URLS_PER_MESSAGE);
+    if skipped > 0 {
+        info!(
+            "Too many links in the message, skipping {} of them",
+            skipped
+        );
+        urls.truncate(MAX_URLS_PER_MESSAGE);
+    }
 
-    debug!("Extracted URL: {}", url);
+    let chat_output_mode = context
+        .chat_output_modes
+        .lock()
+        .await
+        .get(&chat.id())
+        .copied();
+    let output_mode = options
+        .output_mode
+        .or(chat_output_mode)
+        .filter(|_| permissions.extra_options)
+        .unwrap_or_default();
 
-    let Some(downloader) = dispatcher.find_downloader(&url) else {
-        return reply(Lang::UnsupportedUrl);
+    let several_urls = urls.len() > 1;
+    let authorized = roles::authorize_downloads(
+        context,
+        sender,
+        role,
+        urls,
+        None,
+        DownloadOptions {
+            subtitles: options.subtitles.clone(),
+            ..Default::default()
+        },
+    )
+    .await?;
+    let (mut items, unsupported) = match authorized {
+        Ok(authorized) => {
+            let items = authorized
+                .items
+                .into_iter()
+                .map(|(url, downloader)| {
+                    debug!("Found downloader for {}: {:?}", url, downloader);
+                    JobItem {
+                        url,
+                        downloader,
+                        options: authorized.options.clone(),
+                        output_mode,
+                    }
+                })
+                .collect::<Vec<_>>();
+            (items, authorized.unsupported)
+        }
+        Err(roles::DownloadsDenied::Unsupported) if several_urls => {
+            return reply_if_addressed(Lang::UnsupportedUrls)
+        }
+        Err(roles::DownloadsDenied::Unsupported) => {
+            return reply_if_addressed(Lang::UnsupportedUrl)
+        }
+        Err(roles::DownloadsDenied::QuotaExceeded(limit)) => {
+            return reply_if_addressed(Lang::QuotaExceeded(limit))
+        }
     };
 
-    debug!("Found downloader: {:?}", downloader);
+    match items.as_slice() {
+        // the format can only be picked for a single video, the keyboard would get confusing otherwise
+        [item] if options.pick_format && unsupported.is_empty() => {
+            let formats = item
+                .downloader
+                .clone()
+                .list_formats(item.url.clone())
+                .await
+                .whatever_context("Listing the available formats")?;
+            if !formats.is_empty() {
+                let item = items.pop().unwrap();
+                picker::offer_formats(context, message, sender, item, formats).await?;
+                return Ok(MessageResult::Ignore);
+            }
+            debug!("Downloader does not support choosing the format, downloading the default one");
+        }
+        _ => {}
+    }
 
-    let status_message = message
-        .reply(Lang::StatusWorking)
+    run_job(
+        context,
+        message,
+        sender,
+        JobRequest {
+            items,
+            unsupported,
+            skipped,
+        },
+    )
+    .await?;
+
+    Ok(MessageResult::Ignore)
+}
+
+/// A job that has failed, kept around for a while so that it can be retried with a button
+#[derive(Clone)]
+pub struct FailedJob {
+    pub owner: UserId,
+    pub item: JobItem,
+}
+
+/// Links found in a single message
+pub struct JobRequest {
+    pub items: Vec<JobItem>,
+    /// Links no downloader can handle, they are only listed in the summary
+    pub unsupported: Vec<Url>,
+    /// Number of links over [MAX_URLS_PER_MESSAGE]
+    pub skipped: usize,
+}
+
+impl JobRequest {
+    pub fn single(item: JobItem) -> Self {
+        Self {
+            items: vec![item],
+            unsupported: Vec::new(),
+            skipped: 0,
+        }
+    }
+}
+
+/// Downloads the videos and sends them as replies to `request_message`, reporting the progress in a single status message
+#[instrument(skip_all, fields(urls = ?request.items.iter().map(|item| item.url.as_str()).collect::<Vec<_>>()))]
+pub async fn run_job(
+    context: &BotContext,
+    request_message: &Message,
+    owner: UserId,
+    request: JobRequest,
+) -> Result<(), Whatever> {
+    let JobRequest {
+        items,
+        unsupported,
+        skipped,
+    } = request;
+    let chat_id = Some(request_message.chat().id());
+    let job = context.jobs.start(owner);
+
+    let status_message = context
+        .scheduler
+        .run_required(chat_id, || {
+            request_message.reply(
+                InputMessage::text(Lang::StatusWorking.to_string())
+                    .reply_markup(&callback::cancel_markup(job.id)),
+            )
+        })
         .await
         .whatever_context("Sending reply")?;
 
-    let end_message = match upload::upload_with_status_updates(
-        &client,
-        &message,
+    let cancels = items
+        .iter()
+        .map(|item| {
+            let cancel = job.cancel.child_token();
+            // editing the link out of the message cancels its item
+            message_jobs::register_job(context, request_message, &item.url, cancel.clone());
+            cancel
+        })
+        .collect::<Vec<_>>();
+
+    let results = upload::upload_with_status_updates(
+        context,
+        request_message,
         &status_message,
-        url,
-        downloader,
-        video_handling_timeout,
+        &items,
+        &cancels,
+        &job,
     )
-    .await
-    {
-        Ok(_) => {
-            info!("Successfully sent video!");
-            Lang::ResultSuccess
-        }
-        Err(UploadError::Timeout) => {
-            warn!("Took too long to handle a message, stopped video handling");
-            Lang::ResultErrorTimeout
-        }
-        Err(UploadError::Other { source: e }) => {
-            error!("Error occurred while sending the video: {:?}", e);
-            return Err(e);
+    .await?;
+
+    // a single link gets a plain result, as there's nothing to tell apart
+    let single = items.len() == 1 && unsupported.is_empty() && skipped == 0;
+
+    // the downloads that didn't happen don't count towards the daily limit
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    if failed > 0 {
+        if let Err(e) = context.download_quotas.refund(owner, failed as u32).await {
+            warn!("Could not give back the downloads of {:?}: {}", owner, e);
         }
-    };
+    }
+
+    let mut sections = Vec::new();
+    let mut retry_buttons = Vec::new();
+    for (index, (item, result)) in items.into_iter().zip(results).enumerate() {
+        let url = item.url.to_string();
+        let outcome = match result {
+            Ok(()) => {
+                info!("Successfully sent video for {}!", url);
+                Lang::ResultSuccess
+            }
+            Err(UploadError::Timeout) => {
+                warn!("Took too long to handle {}, stopped video handling", url);
+                Lang::ResultErrorTimeout
+            }
+            Err(UploadError::Cancelled) => {
+                info!("The job was cancelled by the user");
+                Lang::ResultCancelled
+            }
+            Err(UploadError::Other { source: e }) => {
+                let report = snafu::Report::from_error(e).to_string();
+                error!(
+                    "Error occurred while sending the video for {}: {}",
+                    url, report
+                );
+
+                // every failed item can be retried on its own
+                let retry_job = context.jobs.allocate_id();
+                retry_buttons.push(callback::RetryButtons {
+                    job: retry_job,
+                    number: (!single).then_some(index + 1),
+                    has_next_downloader: context
+                        .dispatcher
+                        .find_next_downloader(&item.url, &item.downloader)
+                        .is_some(),
+                });
+                context
+                    .failed_jobs
+                    .lock()
+                    .unwrap()
+                    .insert(retry_job, FailedJob { owner, item });
+
+                Lang::ResultGenericError(report)
+            }
+        };
+
+        sections.push(if single {
+            outcome.to_string()
+        } else {
+            Lang::ResultItem(index + 1, url, outcome.to_string()).to_string()
+        });
+    }
+    if !unsupported.is_empty() {
+        let list = unsupported
+            .iter()
+            .map(Url::as_str)
+            .collect::<Vec<_>>()
+            .join("\n");
+        sections.push(Lang::ResultUnsupportedList(list).to_string());
+    }
+    if skipped > 0 {
+        sections.push(Lang::ResultSkippedUrls(skipped, MAX_URLS_PER_MESSAGE).to_string());
+    }
 
-    status_message
-        .edit(end_message)
+    let mut end_message = InputMessage::text(sections.join("\n\n"));
+    if !retry_buttons.is_empty() {
+        end_message = end_message.reply_markup(&callback::retry_markup(&retry_buttons));
+    }
+
+    context
+        .scheduler
+        .run(chat_id, RequestKind::Required, || {
+            status_message.edit(end_message.clone())
+        })
         .await
         .whatever_context("Editing message")?;
 
-    Ok(MessageResult::Ignore)
+    Ok(())
 }
 
-#[instrument(skip_all, fields(chat_id = message.chat().id(), username = message.chat().username()), err(Debug))]
+#[instrument(skip_all, fields(chat_id = message.chat().id(), username = message.chat().username(), edited), err(Debug))]
 async fn handle_message(
     message: Message,
-    client: Client,
-    dispatcher: Arc<DownloadDispatcher>,
-    whitelist: Arc<Mutex<whitelist::Whitelist>>,
-    superusers: Arc<HashSet<UserId>>,
-    video_handling_timeout: Duration,
+    context: Arc<BotContext>,
+    edited: bool,
 ) -> Result<(), Whatever> {
-    let result = handle_message_impl(
-        &message,
-        client,
-        dispatcher,
-        whitelist,
-        superusers,
-        video_handling_timeout,
-    )
-    .await;
+    let result = handle_message_impl(&message, &context, edited).await;
+    let chat_id = Some(message.chat().id());
 
     // reply to the user if there's an error or the handler requested a reply
     // any error here will only be reported to the tracing, not to the user (because sending a message after a failed message will probably fail too..)
     match result {
         Ok(MessageResult::Reply(reply)) => {
-            message
-                .reply(reply)
+            context
+                .scheduler
+                .run(chat_id, RequestKind::Required, || {
+                    message.reply(reply.clone())
+                })
                 .await
                 .whatever_context("Replying to the message")?;
         }
@@ -233,8 +751,12 @@ async fn handle_message(
             // TODO: make the error a code block
             // the markdown parser seems a bit buggy, so can't really use it here.
             // TODO: and now that a Lang is here, it's even less clear as to how
-            message
-                .reply(Lang::ResultGenericError(report))
+            let error_message = Lang::ResultGenericError(report).to_string();
+            context
+                .scheduler
+                .run(chat_id, RequestKind::Required, || {
+                    message.reply(error_message.as_str())
+                })
                 .await
                 .whatever_context("Sending the error message to the user")?;
         }
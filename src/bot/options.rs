@@ -0,0 +1,110 @@
+//! Keywords the user can put into the message next to the link to tweak how it's handled
+
+use serde::{Deserialize, Serialize};
+
+use crate::downloader::SubtitlesRequest;
+
+/// How the video is sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    /// A regular video
+    #[default]
+    Video,
+    /// A round video note, cropped to a square. Only for clips under a minute
+    Round,
+    /// A silent looping animation, like a GIF
+    Animation,
+}
+
+impl OutputMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "video" => Some(Self::Video),
+            "round" | "note" => Some(Self::Round),
+            "gif" | "animation" => Some(Self::Animation),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Video => "video",
+            Self::Round => "round",
+            Self::Animation => "gif",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MessageOptions {
+    /// Let the user choose the format from a keyboard instead of picking the best one automatically
+    pub pick_format: bool,
+    /// `subs` or `subs:<language>`, like `subs:en`
+    pub subtitles: Option<SubtitlesRequest>,
+    /// `mode:<mode>`, like `mode:round`, overrides the output mode of the chat
+    pub output_mode: Option<OutputMode>,
+}
+
+impl MessageOptions {
+    pub fn parse(text: &str) -> Self {
+        let mut options = Self::default();
+
+        for word in text.split_whitespace() {
+            match word.to_lowercase().as_str() {
+                "pick" | "quality" => options.pick_format = true,
+                "subs" => options.subtitles = Some(SubtitlesRequest { language: None }),
+                word => {
+                    if let Some(language) = word.strip_prefix("subs:").filter(|l| !l.is_empty()) {
+                        options.subtitles = Some(SubtitlesRequest {
+                            language: Some(language.to_string()),
+                        });
+                    } else if let Some(mode) = word.strip_prefix("mode:") {
+                        // the words are common, so only the prefixed ones count
+                        if let Some(mode) = OutputMode::parse(mode) {
+                            options.output_mode = Some(mode);
+                        }
+                    }
+                }
+            }
+        }
+
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_mode_needs_the_prefix() {
+        assert_eq!(MessageOptions::parse("round gif video").output_mode, None);
+        assert_eq!(
+            MessageOptions::parse("look mode:round").output_mode,
+            Some(OutputMode::Round)
+        );
+        assert_eq!(
+            MessageOptions::parse("MODE:GIF").output_mode,
+            Some(OutputMode::Animation)
+        );
+        assert_eq!(
+            MessageOptions::parse("mode:video").output_mode,
+            Some(OutputMode::Video)
+        );
+        assert_eq!(MessageOptions::parse("mode:square").output_mode, None);
+    }
+
+    #[test]
+    fn parses_subtitles() {
+        assert_eq!(
+            MessageOptions::parse("subs").subtitles.unwrap().language,
+            None
+        );
+        assert_eq!(
+            MessageOptions::parse("subs:en").subtitles.unwrap().language,
+            Some("en".to_string())
+        );
+        assert!(MessageOptions::parse("subs:").subtitles.is_none());
+    }
+}
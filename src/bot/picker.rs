@@ -0,0 +1,158 @@
+//! Lets the user choose the video format from an inline keyboard before the download starts
+
+use std::{sync::Arc, time::Duration};
+
+use grammers_client::{button, reply_markup, types::Message, InputMessage};
+use snafu::ResultExt;
+use tracing::debug;
+
+use crate::{
+    bot::{
+        callback::CallbackAction, jobs::JobId, lang::Lang, run_job, scheduler::RequestKind,
+        upload::JobItem, BotContext, JobRequest, UserId,
+    },
+    downloader::{DownloadOptions, FormatInfo},
+    whatever::Whatever,
+};
+
+/// How long the user has to make a choice
+pub const PENDING_CHOICE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// A format choice offered to the user, waiting for a button press
+pub struct PendingChoice {
+    owner: UserId,
+    item: JobItem,
+    formats: Vec<FormatInfo>,
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB"];
+
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn format_button_text(format: &FormatInfo) -> String {
+    match format.size {
+        Some(size) => format!("{} · {}", format.label, format_size(size)),
+        None => format.label.clone(),
+    }
+}
+
+/// Replies with a keyboard listing the formats, the download starts when one of them is pressed
+pub async fn offer_formats(
+    context: &BotContext,
+    message: &Message,
+    owner: UserId,
+    item: JobItem,
+    formats: Vec<FormatInfo>,
+) -> Result<(), Whatever> {
+    let choice = context.jobs.allocate_id();
+
+    let markup = reply_markup::inline(
+        formats
+            .iter()
+            .enumerate()
+            .map(|(index, format)| {
+                vec![button::inline(
+                    format_button_text(format),
+                    CallbackAction::PickFormat(choice, index).to_data(),
+                )]
+            })
+            .collect(),
+    );
+
+    context.pending_choices.lock().unwrap().insert(
+        choice,
+        PendingChoice {
+            owner,
+            item,
+            formats,
+        },
+    );
+
+    context
+        .scheduler
+        .run(Some(message.chat().id()), RequestKind::Required, || {
+            message.reply(InputMessage::from(Lang::PickFormat).reply_markup(&markup))
+        })
+        .await
+        .whatever_context("Sending the format keyboard")?;
+
+    Ok(())
+}
+
+/// Starts the download of the format the user picked
+pub async fn pick_format(
+    picker_message: Message,
+    context: &Arc<BotContext>,
+    sender: UserId,
+    is_manager: bool,
+    choice: JobId,
+    index: usize,
+) -> Result<Lang, Whatever> {
+    let mut pending_choices = context.pending_choices.lock().unwrap();
+    let Some(pending) = pending_choices.get(&choice) else {
+        return Ok(Lang::CallbackExpired);
+    };
+    if pending.owner != sender && !is_manager {
+        return Ok(Lang::CallbackNotAllowed);
+    }
+    if index >= pending.formats.len() {
+        return Ok(Lang::CallbackExpired);
+    }
+    // only one format can be picked
+    let pending = pending_choices.remove(&choice).unwrap();
+    drop(pending_choices);
+
+    let format = &pending.formats[index];
+    debug!("User picked format {:?} for {}", format, pending.item.url);
+
+    let chosen_text = Lang::PickFormatChosen(format_button_text(format)).to_string();
+    context
+        .scheduler
+        .run(
+            Some(picker_message.chat().id()),
+            RequestKind::Required,
+            || picker_message.edit(chosen_text.as_str()),
+        )
+        .await
+        .whatever_context("Removing the format keyboard")?;
+
+    // the keyboard is a reply to the message with the link, the video should be sent as a reply to it too
+    let request_message = match picker_message
+        .get_reply()
+        .await
+        .whatever_context("Loading the request message")?
+    {
+        Some(message) => message,
+        None => picker_message,
+    };
+
+    let item = JobItem {
+        options: DownloadOptions {
+            format_id: Some(format.id.clone()),
+            ..pending.item.options
+        },
+        ..pending.item
+    };
+    let context = context.clone();
+    tokio::spawn(async move {
+        // error are logged by tracing instrument macro
+        let _ = run_job(
+            &context,
+            &request_message,
+            pending.owner,
+            JobRequest::single(item),
+        )
+        .await;
+    });
+
+    Ok(Lang::CallbackPicked)
+}
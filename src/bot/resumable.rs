@@ -0,0 +1,282 @@
+//! Uploads files to telegram part by part, retrying only the parts that failed
+//!
+//! `Client::upload_stream` gives up on the whole file as soon as a single part fails to upload, which is quite wasteful for large videos.
+//! Here we keep the not-yet-acknowledged parts in memory, so they can be re-sent under the same file id.
+
+use std::{future::Future, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use grammers_client::{types::media::Uploaded, Client};
+use grammers_tl_types as tl;
+use snafu::{whatever, ResultExt};
+use tracing::{debug, warn};
+
+use crate::{
+    bot::scheduler::{RequestKind, Scheduler},
+    whatever::Whatever,
+};
+
+/// Size of a single part, the maximum allowed by telegram
+const PART_SIZE: usize = 512 * 1024;
+/// Files larger than this must be uploaded with `upload.saveBigFilePart`
+const BIG_FILE_SIZE: usize = 10 * 1024 * 1024;
+/// How many parts are uploaded concurrently
+const PARALLEL_PARTS: usize = 4;
+const MAX_PART_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Splits the stream into parts of exactly `PART_SIZE` bytes (except for the last one)
+fn split_into_parts(
+    stream: BoxStream<'static, futures::io::Result<Bytes>>,
+) -> impl Stream<Item = futures::io::Result<Bytes>> {
+    async_stream::try_stream! {
+        let mut buffer = BytesMut::with_capacity(PART_SIZE);
+
+        for await chunk in stream {
+            let mut chunk = chunk?;
+            while !chunk.is_empty() {
+                let take = (PART_SIZE - buffer.len()).min(chunk.len());
+                buffer.extend_from_slice(&chunk.split_to(take));
+                if buffer.len() == PART_SIZE {
+                    yield buffer.split().freeze();
+                }
+            }
+        }
+
+        if !buffer.is_empty() {
+            yield buffer.freeze();
+        }
+    }
+}
+
+struct FilePart<'a> {
+    file_id: i64,
+    index: usize,
+    total_parts: usize,
+    big: bool,
+    bytes: &'a Bytes,
+}
+
+impl FilePart<'_> {
+    async fn send(&self, client: &Client, scheduler: &Scheduler) -> Result<bool, Whatever> {
+        let saved = scheduler
+            .run(None, RequestKind::Upload, || async {
+                if self.big {
+                    client
+                        .invoke(&tl::functions::upload::SaveBigFilePart {
+                            file_id: self.file_id,
+                            file_part: self.index as i32,
+                            file_total_parts: self.total_parts as i32,
+                            bytes: self.bytes.to_vec(),
+                        })
+                        .await
+                } else {
+                    client
+                        .invoke(&tl::functions::upload::SaveFilePart {
+                            file_id: self.file_id,
+                            file_part: self.index as i32,
+                            bytes: self.bytes.to_vec(),
+                        })
+                        .await
+                }
+            })
+            .await
+            .whatever_context("Saving file part")?;
+
+        // upload requests are never dropped
+        Ok(saved.unwrap_or(false))
+    }
+
+    /// Sends the part until telegram acknowledges it
+    async fn send_with_retries(
+        &self,
+        client: &Client,
+        scheduler: &Scheduler,
+    ) -> Result<(), Whatever> {
+        retry_part(self.index, || self.send(client, scheduler)).await
+    }
+}
+
+/// Runs `send` until it reports the part as acknowledged, backing off exponentially between the attempts
+async fn retry_part<F, Fut>(index: usize, mut send: F) -> Result<(), Whatever>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool, Whatever>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match send().await {
+            Ok(true) => return Ok(()),
+            Ok(false) => warn!(
+                "Telegram did not acknowledge part {} (attempt {})",
+                index, attempt
+            ),
+            Err(e) => warn!(
+                "Failed to upload part {} (attempt {}): {:?}",
+                index, attempt, e
+            ),
+        }
+
+        if attempt == MAX_PART_ATTEMPTS {
+            whatever!("Giving up on part {} after {} attempts", index, attempt);
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Uploads a stream of known size to telegram, returning a handle that can be used to send it as media.
+pub async fn upload_stream(
+    client: &Client,
+    scheduler: &Scheduler,
+    stream: BoxStream<'static, futures::io::Result<Bytes>>,
+    size: usize,
+    name: String,
+) -> Result<Uploaded, Whatever> {
+    upload_input_file(client, scheduler, stream, size, name)
+        .await
+        .map(Uploaded::from_raw)
+}
+
+/// Same as [upload_stream], but returns the raw file for the requests grammers has no wrappers for
+pub async fn upload_input_file(
+    client: &Client,
+    scheduler: &Scheduler,
+    stream: BoxStream<'static, futures::io::Result<Bytes>>,
+    size: usize,
+    name: String,
+) -> Result<tl::enums::InputFile, Whatever> {
+    let file_id = rand::random::<i64>();
+    let total_parts = size.div_ceil(PART_SIZE);
+    let big = size > BIG_FILE_SIZE;
+
+    debug!(
+        "Uploading {} bytes in {} parts (file id {})",
+        size, total_parts, file_id
+    );
+
+    let mut acknowledged = vec![false; total_parts];
+
+    let mut uploads = split_into_parts(stream)
+        .enumerate()
+        .map(|(index, bytes)| async move {
+            let bytes = bytes.whatever_context("Reading the file part")?;
+            if index >= total_parts {
+                whatever!("The stream is longer than the declared {} bytes", size);
+            }
+
+            let part = FilePart {
+                file_id,
+                index,
+                total_parts,
+                big,
+                bytes: &bytes,
+            };
+            part.send_with_retries(client, scheduler).await?;
+
+            Ok::<_, Whatever>(index)
+        })
+        .buffer_unordered(PARALLEL_PARTS);
+
+    while let Some(index) = uploads.try_next().await? {
+        acknowledged[index] = true;
+    }
+
+    if let Some(missing) = acknowledged.iter().position(|acked| !acked) {
+        whatever!(
+            "The stream ended before part {} of {} was read",
+            missing,
+            total_parts
+        );
+    }
+
+    let input_file: tl::enums::InputFile = if big {
+        tl::types::InputFileBig {
+            id: file_id,
+            parts: total_parts as i32,
+            name,
+        }
+        .into()
+    } else {
+        tl::types::InputFile {
+            id: file_id,
+            parts: total_parts as i32,
+            name,
+            md5_checksum: String::new(),
+        }
+        .into()
+    };
+
+    Ok(input_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use futures::stream;
+    use snafu::FromString;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_until_acknowledged() {
+        let attempts = Cell::new(0);
+        let start = Instant::now();
+
+        retry_part(0, || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                match attempt {
+                    1 => Err(Whatever::without_source("network blip".to_string())),
+                    2 => Ok(false),
+                    _ => Ok(true),
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(attempts.get(), 3);
+        // backed off twice: 500ms, then 1s
+        assert_eq!(start.elapsed(), INITIAL_BACKOFF * 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_attempts() {
+        let attempts = Cell::new(0);
+
+        let result = retry_part(7, || {
+            attempts.set(attempts.get() + 1);
+            async { Ok(false) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), MAX_PART_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn splits_into_parts_of_fixed_size() {
+        let chunks = vec![
+            Ok(Bytes::from(vec![1; PART_SIZE / 2 + 1])),
+            Ok(Bytes::from(vec![2; PART_SIZE])),
+            Ok(Bytes::from(vec![3; 10])),
+        ];
+        let parts = split_into_parts(stream::iter(chunks).boxed())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        let sizes = parts.iter().map(Bytes::len).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![PART_SIZE, PART_SIZE / 2 + 11]);
+        assert_eq!(parts[0][PART_SIZE / 2 + 1], 2);
+    }
+}
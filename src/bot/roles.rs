@@ -0,0 +1,466 @@
+//! Roles of the users and what they are allowed to do
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use snafu::ResultExt;
+use tokio::sync::Mutex;
+use tracing::info;
+use url::Url;
+
+use crate::{
+    bot::{access_groups, BotContext, UserId},
+    downloader::{DownloadOptions, Downloader},
+    state_store::{Collection, StateStore},
+    whatever::Whatever,
+};
+
+#[derive(
+    Debug, Serialize, Deserialize, PartialEq, Eq, Ord, PartialOrd, Copy, Clone, Hash, Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Power,
+    #[default]
+    User,
+    /// Members of the groups that let everyone use the bot, who are not whitelisted themselves
+    Guest,
+}
+
+impl Role {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "admin" => Some(Self::Admin),
+            "power" => Some(Self::Power),
+            "user" => Some(Self::User),
+            "guest" => Some(Self::Guest),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Power => "power",
+            Self::User => "user",
+            Self::Guest => "guest",
+        }
+    }
+
+    /// Permissions used when the config does not override them
+    fn default_permissions(self) -> Permissions {
+        match self {
+            Self::Admin => Permissions {
+                manage_users: true,
+                ..Default::default()
+            },
+            Self::Power => Permissions::default(),
+            Self::User => Permissions {
+                max_size: Some(500 * 1024 * 1024),
+                daily_downloads: Some(50),
+                ..Default::default()
+            },
+            Self::Guest => Permissions {
+                max_size: Some(50 * 1024 * 1024),
+                extra_options: false,
+                daily_downloads: Some(10),
+                commands: Some(["dl".to_string()].into()),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Permissions {
+    /// Managing the whitelist, the invites and the roles, deciding on the access requests and cancelling the jobs of others
+    pub manage_users: bool,
+    /// Names of the downloaders the role can use (like `youtube`), all of them when not set
+    pub downloaders: Option<HashSet<String>>,
+    /// Largest video the role can download, in bytes. Only the global limit applies when not set
+    pub max_size: Option<u64>,
+    /// Picking the format, subtitles and the round/gif output modes
+    pub extra_options: bool,
+    /// Downloads a day (UTC), unlimited when not set
+    pub daily_downloads: Option<u32>,
+    /// Names of the commands the role can use (like `mode`), all of them when not set.
+    /// The other permissions still apply, the users commands need [Self::manage_users]
+    pub commands: Option<HashSet<String>>,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self {
+            manage_users: false,
+            downloaders: None,
+            max_size: None,
+            extra_options: true,
+            daily_downloads: None,
+            commands: None,
+        }
+    }
+}
+
+impl Permissions {
+    pub fn allows_downloader(&self, downloader: &dyn Downloader) -> bool {
+        self.downloaders
+            .as_ref()
+            .map_or(true, |downloaders| downloaders.contains(downloader.name()))
+    }
+
+    /// `command` is the name without the slash, like `mode`
+    pub fn allows_command(&self, command: &str) -> bool {
+        self.commands
+            .as_ref()
+            .map_or(true, |commands| commands.contains(command))
+    }
+}
+
+/// Permissions of a role set in the config, the fields that are not set keep the defaults of the role
+///
+/// The limits can be set to `null` to remove the default one, which is why they are doubly optional.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PermissionsOverride {
+    pub manage_users: Option<bool>,
+    #[serde(deserialize_with = "explicit_null")]
+    pub downloaders: Option<Option<HashSet<String>>>,
+    #[serde(deserialize_with = "explicit_null")]
+    pub max_size: Option<Option<u64>>,
+    pub extra_options: Option<bool>,
+    #[serde(deserialize_with = "explicit_null")]
+    pub daily_downloads: Option<Option<u32>>,
+    #[serde(deserialize_with = "explicit_null")]
+    pub commands: Option<Option<HashSet<String>>>,
+}
+
+/// Tells a `null` (`Some(None)`) apart from a missing field, which stays `None` through `#[serde(default)]`
+fn explicit_null<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl PermissionsOverride {
+    fn apply(self, permissions: Permissions) -> Permissions {
+        Permissions {
+            manage_users: self.manage_users.unwrap_or(permissions.manage_users),
+            downloaders: self.downloaders.unwrap_or(permissions.downloaders),
+            max_size: self.max_size.unwrap_or(permissions.max_size),
+            extra_options: self.extra_options.unwrap_or(permissions.extra_options),
+            daily_downloads: self.daily_downloads.unwrap_or(permissions.daily_downloads),
+            commands: self.commands.unwrap_or(permissions.commands),
+        }
+    }
+}
+
+/// Permissions of every role, the ones from the config applied over the defaults
+pub fn resolve_permissions(
+    mut configured: HashMap<Role, PermissionsOverride>,
+) -> HashMap<Role, Permissions> {
+    [Role::Admin, Role::Power, Role::User, Role::Guest]
+        .into_iter()
+        .map(|role| {
+            let overrides = configured.remove(&role).unwrap_or_default();
+            (role, overrides.apply(role.default_permissions()))
+        })
+        .collect()
+}
+
+/// Role of the user, `None` if they have no access at all
+///
+/// The superusers from the config are always admins, the users who are not whitelisted
+/// get the role of the access group they are in.
+/// Doesn't know about the groups that let everyone in, their members are [Role::Guest] there.
+pub async fn user_role(context: &BotContext, user: UserId) -> Option<Role> {
+    if context.superusers.contains(&user) {
+        return Some(Role::Admin);
+    }
+    let whitelisted = context
+        .whitelist
+        .lock()
+        .await
+        .get(&user)
+        .map(|info| info.role);
+    if whitelisted.is_some() {
+        return whitelisted;
+    }
+
+    let access_hash = context.known_users.lock().unwrap().get(&user).copied();
+    access_groups::access_group_role(context, user, access_hash).await
+}
+
+pub fn permissions(context: &BotContext, role: Role) -> &Permissions {
+    &context.roles[&role]
+}
+
+/// Returns true if the user is allowed to manage the others
+pub async fn can_manage_users(context: &BotContext, user: UserId) -> bool {
+    match user_role(context, user).await {
+        Some(role) => permissions(context, role).manage_users,
+        None => false,
+    }
+}
+
+/// Downloads the user is allowed to start, see [authorize_downloads]
+pub struct AuthorizedDownloads {
+    pub items: Vec<(Url, Arc<dyn Downloader>)>,
+    /// The requested options, limited by the role
+    pub options: DownloadOptions,
+    /// Links no downloader allowed for the role can handle
+    pub unsupported: Vec<Url>,
+}
+
+pub enum DownloadsDenied {
+    /// None of the links can be downloaded with the downloaders allowed for the role
+    Unsupported,
+    /// All the downloads the role has a day are used up
+    QuotaExceeded(u32),
+}
+
+/// Checks what the user may download with their role, for every way a download can be started
+///
+/// Picks the first downloader allowed for the role for every link, or the first one after `after`
+/// when retrying with another one. Limits the size of the videos and takes the downloads from the daily quota,
+/// they have to be given back with [DownloadQuotas::refund] if they don't happen.
+pub async fn authorize_downloads(
+    context: &BotContext,
+    user: UserId,
+    role: Role,
+    urls: Vec<Url>,
+    after: Option<&Arc<dyn Downloader>>,
+    options: DownloadOptions,
+) -> Result<Result<AuthorizedDownloads, DownloadsDenied>, Whatever> {
+    let permissions = permissions(context, role);
+
+    let mut items = Vec::new();
+    let mut unsupported = Vec::new();
+    for url in urls {
+        let downloader = context
+            .dispatcher
+            .downloaders_for(&url, after)
+            .find(|downloader| permissions.allows_downloader(downloader.as_ref()))
+            .cloned();
+        match downloader {
+            Some(downloader) => items.push((url, downloader)),
+            None => {
+                info!("No downloader allowed for {:?} supports {}", role, url);
+                unsupported.push(url)
+            }
+        }
+    }
+    if items.is_empty() {
+        return Ok(Err(DownloadsDenied::Unsupported));
+    }
+
+    if !context
+        .download_quotas
+        .try_take(user, items.len() as u32, permissions.daily_downloads)
+        .await?
+    {
+        info!("{:?} ran out of the daily downloads", user);
+        return Ok(Err(DownloadsDenied::QuotaExceeded(
+            permissions.daily_downloads.unwrap_or_default(),
+        )));
+    }
+
+    Ok(Ok(AuthorizedDownloads {
+        items,
+        options: DownloadOptions {
+            max_size: permissions.max_size,
+            ..options
+        },
+        unsupported,
+    }))
+}
+
+/// Downloads of every user for the current day
+type DownloadCounts = HashMap<UserId, (NaiveDate, u32)>;
+
+/// Counts the downloads of every user for the current day, keeping the counts in the state store
+pub struct DownloadQuotas {
+    store: Arc<dyn StateStore>,
+    counts: Mutex<DownloadCounts>,
+}
+
+impl DownloadQuotas {
+    pub async fn load(store: Arc<dyn StateStore>) -> Result<Self, Whatever> {
+        let counts = store
+            .load_as(Collection::DownloadQuotas)
+            .await
+            .whatever_context("Loading the download quotas")?;
+        Ok(Self {
+            store,
+            counts: Mutex::new(counts),
+        })
+    }
+
+    /// Counts `downloads` more downloads of the user, unless they would go over the `limit`
+    ///
+    /// Returns false if the limit would be exceeded, nothing is counted then.
+    pub async fn try_take(
+        &self,
+        user: UserId,
+        downloads: u32,
+        limit: Option<u32>,
+    ) -> Result<bool, Whatever> {
+        let Some(limit) = limit else {
+            return Ok(true);
+        };
+
+        let mut counts = self.counts.lock().await;
+        if !take(&mut counts, Utc::now().date_naive(), user, downloads, limit) {
+            return Ok(false);
+        }
+        self.store(&counts).await?;
+        Ok(true)
+    }
+
+    /// Gives back the downloads that failed or were cancelled
+    pub async fn refund(&self, user: UserId, downloads: u32) -> Result<(), Whatever> {
+        let mut counts = self.counts.lock().await;
+        if refund(&mut counts, Utc::now().date_naive(), user, downloads) {
+            self.store(&counts).await?;
+        }
+        Ok(())
+    }
+
+    async fn store(&self, counts: &DownloadCounts) -> Result<(), Whatever> {
+        self.store
+            .store_as(Collection::DownloadQuotas, counts)
+            .await
+            .whatever_context("Storing the download quotas")
+    }
+}
+
+/// Returns false if the downloads don't fit into the limit, nothing is counted then
+fn take(
+    counts: &mut DownloadCounts,
+    today: NaiveDate,
+    user: UserId,
+    downloads: u32,
+    limit: u32,
+) -> bool {
+    // the counts from the previous days are not needed anymore
+    counts.retain(|_, (day, _)| *day == today);
+
+    let (_, count) = counts.entry(user).or_insert((today, 0));
+    if *count + downloads > limit {
+        return false;
+    }
+    *count += downloads;
+    true
+}
+
+/// Returns false if there was nothing to give back, the downloads may be from the previous day
+fn refund(counts: &mut DownloadCounts, today: NaiveDate, user: UserId, downloads: u32) -> bool {
+    match counts.get_mut(&user) {
+        Some((day, count)) if *day == today && *count > 0 => {
+            *count = count.saturating_sub(downloads);
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: UserId = UserId(1);
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 5, day).unwrap()
+    }
+
+    #[test]
+    fn overrides_keep_the_role_defaults() {
+        let configured = [
+            (
+                Role::Admin,
+                PermissionsOverride {
+                    max_size: Some(Some(1)),
+                    ..Default::default()
+                },
+            ),
+            (
+                Role::Guest,
+                PermissionsOverride {
+                    daily_downloads: Some(Some(3)),
+                    ..Default::default()
+                },
+            ),
+        ]
+        .into();
+        let permissions = resolve_permissions(configured);
+
+        let admin = &permissions[&Role::Admin];
+        assert!(admin.manage_users);
+        assert_eq!(admin.max_size, Some(1));
+
+        let guest = &permissions[&Role::Guest];
+        assert_eq!(guest.daily_downloads, Some(3));
+        assert_eq!(guest.max_size, Some(50 * 1024 * 1024));
+        assert!(!guest.extra_options);
+        assert!(guest.allows_command("dl"));
+        assert!(!guest.allows_command("mode"));
+
+        // the roles missing from the config get the defaults
+        let power = &permissions[&Role::Power];
+        assert!(!power.manage_users);
+        assert_eq!(power.daily_downloads, None);
+        assert!(power.allows_command("mode"));
+    }
+
+    #[test]
+    fn null_overrides_remove_the_role_defaults() {
+        let guest: PermissionsOverride = serde_json::from_value(serde_json::json!({
+            "commands": null,
+            "max_size": null,
+        }))
+        .unwrap();
+        let permissions = resolve_permissions([(Role::Guest, guest)].into());
+
+        let guest = &permissions[&Role::Guest];
+        assert_eq!(guest.max_size, None);
+        assert!(guest.allows_command("mode"));
+        assert_eq!(guest.commands, None);
+        // the fields that are not set still keep the defaults
+        assert_eq!(guest.daily_downloads, Some(10));
+        assert!(!guest.extra_options);
+    }
+
+    #[test]
+    fn takes_the_quota_up_to_the_limit() {
+        let mut counts = DownloadCounts::new();
+
+        assert!(take(&mut counts, day(1), USER, 2, 3));
+        assert!(!take(&mut counts, day(1), USER, 2, 3));
+        assert!(take(&mut counts, day(1), USER, 1, 3));
+        assert!(!take(&mut counts, day(1), USER, 1, 3));
+        // the other users have their own quota
+        assert!(take(&mut counts, day(1), UserId(2), 3, 3));
+        // and it starts over every day
+        assert!(take(&mut counts, day(2), USER, 3, 3));
+    }
+
+    #[test]
+    fn refunds_only_the_downloads_of_today() {
+        let mut counts = DownloadCounts::new();
+        assert!(take(&mut counts, day(1), USER, 3, 3));
+
+        assert!(refund(&mut counts, day(1), USER, 2));
+        assert!(take(&mut counts, day(1), USER, 2, 3));
+        assert!(!take(&mut counts, day(1), USER, 1, 3));
+
+        assert!(!refund(&mut counts, day(2), USER, 1));
+        assert!(!refund(&mut counts, day(1), UserId(2), 1));
+    }
+}
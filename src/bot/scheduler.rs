@@ -0,0 +1,208 @@
+//! Coordinates the outgoing requests to telegram
+//!
+//! Telegram limits how often a bot can send messages, both per chat and globally.
+//! Exceeding the limits makes it respond with `FLOOD_WAIT_X` errors, which ask to not send anything for `X` seconds.
+//! All replies and edits go through the [`Scheduler`], which spaces them out and waits out the FLOOD_WAITs.
+
+use std::{collections::HashMap, future::Future, sync::Mutex, time::Duration};
+
+use grammers_client::client::auth::InvocationError;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, warn};
+
+/// Minimal interval between any two rate-limited requests
+const GLOBAL_INTERVAL: Duration = Duration::from_millis(40);
+/// Minimal interval between two rate-limited requests to the same chat
+const CHAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Droppable requests that would have to wait longer than this are dropped instead
+const DROP_THRESHOLD: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RequestKind {
+    /// A request that must be delivered, no matter how long it has to wait (replies, final status edits)
+    Required,
+    /// A request that can be dropped under pressure, because a newer one will supersede it (status message edits)
+    Droppable,
+    /// A request that is not rate-limited, but still has to wait out the FLOOD_WAITs (file parts uploads)
+    Upload,
+}
+
+struct State {
+    flood_wait_until: Instant,
+    global_next_slot: Instant,
+    chat_next_slot: HashMap<i64, Instant>,
+}
+
+pub struct Scheduler {
+    state: Mutex<State>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            state: Mutex::new(State {
+                flood_wait_until: now,
+                global_next_slot: now,
+                chat_next_slot: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Reserves a time slot for the request.
+    ///
+    /// Returns `None` if the request is droppable and would have to wait for too long.
+    fn reserve(&self, chat: Option<i64>, kind: RequestKind) -> Option<Instant> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        // forget about the chats that did not send anything in a while
+        state.chat_next_slot.retain(|_, slot| *slot > now);
+
+        if kind == RequestKind::Upload {
+            return Some(state.flood_wait_until.max(now));
+        }
+
+        let mut slot = now.max(state.flood_wait_until).max(state.global_next_slot);
+        if let Some(chat) = chat {
+            if let Some(&chat_slot) = state.chat_next_slot.get(&chat) {
+                slot = slot.max(chat_slot);
+            }
+        }
+
+        if kind == RequestKind::Droppable && slot > now + DROP_THRESHOLD {
+            return None;
+        }
+
+        state.global_next_slot = slot + GLOBAL_INTERVAL;
+        if let Some(chat) = chat {
+            state.chat_next_slot.insert(chat, slot + CHAT_INTERVAL);
+        }
+
+        Some(slot)
+    }
+
+    fn flood_wait(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.flood_wait_until = state.flood_wait_until.max(Instant::now() + duration);
+    }
+
+    /// Runs the request when the rate limits allow it, repeating it if telegram responds with a FLOOD_WAIT.
+    ///
+    /// `chat` is the id of the chat the request is sent to, if any.
+    ///
+    /// Returns `Ok(None)` if the request was dropped.
+    pub async fn run<T, F, Fut>(
+        &self,
+        chat: Option<i64>,
+        kind: RequestKind,
+        mut request: F,
+    ) -> Result<Option<T>, InvocationError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, InvocationError>>,
+    {
+        loop {
+            let Some(slot) = self.reserve(chat, kind) else {
+                debug!("Dropping a request to chat {:?}", chat);
+                return Ok(None);
+            };
+            sleep_until(slot).await;
+
+            match request().await {
+                Err(InvocationError::Rpc(e)) if e.name == "FLOOD_WAIT" => {
+                    let duration = Duration::from_secs(e.value.unwrap_or(1).into());
+                    warn!("Got a FLOOD_WAIT for {:?} (chat {:?})", duration, chat);
+                    self.flood_wait(duration);
+
+                    if kind == RequestKind::Droppable {
+                        return Ok(None);
+                    }
+                }
+                r => return r.map(Some),
+            }
+        }
+    }
+
+    /// Runs a [RequestKind::Required] request, see [Self::run]
+    pub async fn run_required<T, F, Fut>(
+        &self,
+        chat: Option<i64>,
+        request: F,
+    ) -> Result<T, InvocationError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, InvocationError>>,
+    {
+        // required requests only stop on errors, but better safe than panicking
+        self.run(chat, RequestKind::Required, request)
+            .await?
+            .ok_or(InvocationError::Dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_out_requests_to_the_same_chat() {
+        let scheduler = Scheduler::new();
+        let start = Instant::now();
+
+        let first = scheduler.reserve(Some(1), RequestKind::Required).unwrap();
+        let other_chat = scheduler.reserve(Some(2), RequestKind::Required).unwrap();
+        let second = scheduler.reserve(Some(1), RequestKind::Required).unwrap();
+
+        assert_eq!(first, start);
+        // other chats only wait for the global interval
+        assert_eq!(other_chat, start + GLOBAL_INTERVAL);
+        assert_eq!(second, start + CHAT_INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_out_flood_wait() {
+        let scheduler = Scheduler::new();
+        let start = Instant::now();
+        scheduler.flood_wait(Duration::from_secs(5));
+
+        let required = scheduler.reserve(Some(1), RequestKind::Required).unwrap();
+        let upload = scheduler.reserve(None, RequestKind::Upload).unwrap();
+        assert_eq!(required, start + Duration::from_secs(5));
+        assert_eq!(upload, start + Duration::from_secs(5));
+
+        // a shorter FLOOD_WAIT does not cut the longer one short
+        scheduler.flood_wait(Duration::from_secs(1));
+        let later = scheduler.reserve(None, RequestKind::Required).unwrap();
+        assert!(later >= start + Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_droppable_requests_under_pressure() {
+        let scheduler = Scheduler::new();
+        assert!(scheduler.reserve(Some(1), RequestKind::Droppable).is_some());
+
+        scheduler.flood_wait(DROP_THRESHOLD * 2);
+        assert!(scheduler.reserve(Some(1), RequestKind::Droppable).is_none());
+        assert!(scheduler.reserve(Some(1), RequestKind::Required).is_some());
+
+        let dropped: Result<Option<()>, _> = scheduler
+            .run(Some(1), RequestKind::Droppable, || async {
+                panic!("a dropped request must not be sent")
+            })
+            .await;
+        assert!(matches!(dropped, Ok(None)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn runs_required_requests_after_the_wait() {
+        let scheduler = Scheduler::new();
+        scheduler.flood_wait(Duration::from_secs(3));
+        let start = Instant::now();
+
+        let result = scheduler.run_required(Some(1), || async { Ok(42) }).await;
+
+        assert_eq!(result.unwrap(), 42);
+        assert!(start.elapsed() >= Duration::from_secs(3));
+    }
+}
@@ -1,47 +1,73 @@
-use std::{sync::Arc, time::Duration};
+use std::{io, sync::Arc, time::Duration};
 
-use futures::{FutureExt, TryStreamExt};
+use bytes::Bytes;
+use futures::{stream::BoxStream, FutureExt, StreamExt};
 use grammers_client::{
-    types::{Attribute, Message},
-    Client, InputMessage,
+    parsers::parse_markdown_message,
+    types::{media::Uploaded, Attribute, Message},
+    InputMessage,
 };
-use snafu::{FromString, ResultExt, Snafu};
+use grammers_session::PackedChat;
+use grammers_tl_types as tl;
+use snafu::{whatever, FromString, ResultExt, Snafu};
 use tokio::{
     select,
     sync::watch::{Receiver, Sender},
-    time::timeout,
 };
-use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::{debug, info_span, instrument, Instrument};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, instrument, Instrument};
 use url::Url;
 
 use crate::{
-    bot::{lang::Lang, markdown},
-    downloader::{BytesStream, Downloader, VideoDownloadResult},
+    bot::{
+        callback, caption, deadline::Deadline, inline, jobs::JobGuard, lang::Lang, markdown,
+        options::OutputMode, resumable, scheduler::RequestKind, BotContext,
+    },
+    downloader::{BytesStream, DownloadOptions, Downloader, Subtitles, VideoDownloadResult},
+    media::{
+        probe, thumbnail,
+        transcode::{self, Encoding, ROUND_VIDEO_SIZE},
+    },
     whatever::Whatever,
 };
 
 #[derive(Debug, Snafu)]
 pub enum UploadError {
     Timeout,
+    Cancelled,
     Other { source: Whatever },
 }
 
 #[derive(Clone)]
 pub enum UploadStatus {
     FetchingLink,
-    Uploading { progress: f32 },
+    Uploading {
+        progress: f32,
+    },
+    /// Re-encoding the video, see [crate::media::transcode]
+    Transcoding {
+        progress: f32,
+    },
+    /// Re-encoding the video to fit the size limit
+    Compressing {
+        progress: f32,
+    },
+    /// Reported by the bot itself when the item is done, downloaders should not use it
+    Finished {
+        success: bool,
+    },
 }
 
+#[derive(Clone)]
 pub struct UploadNotifier {
-    chan: Sender<UploadStatus>,
+    chan: Arc<Sender<UploadStatus>>,
 }
 
 impl UploadNotifier {
-    fn make() -> (Self, Receiver<UploadStatus>) {
+    pub fn make() -> (Self, Receiver<UploadStatus>) {
         let (tx, rx) = tokio::sync::watch::channel(UploadStatus::FetchingLink);
 
-        (Self { chan: tx }, rx)
+        (Self { chan: Arc::new(tx) }, rx)
     }
 
     pub fn notify_status(&self, status: UploadStatus) -> Result<(), Whatever> {
@@ -51,35 +77,65 @@ impl UploadNotifier {
     }
 }
 
-#[instrument(skip_all, fields(url = %url, downloader_name = downloader.link_text()))]
+/// A single url to download within a job
+#[derive(Clone)]
+pub struct JobItem {
+    pub url: Url,
+    pub downloader: Arc<dyn Downloader>,
+    pub options: DownloadOptions,
+    pub output_mode: OutputMode,
+}
+
+/// Downloads and sends all the items concurrently, reporting their progress in a single status message
+///
+/// `cancels` stop the single items, they must be children of the job cancellation.
+/// Returns the result of every item, in the same order.
+#[instrument(skip_all, fields(items = items.len()))]
 pub async fn upload_with_status_updates(
-    client: &Client,
+    context: &BotContext,
     initial_message: &Message,
     status_message: &Message,
-    url: Url,
-    downloader: Arc<dyn Downloader>,
-    video_handling_timeout: Duration,
-) -> Result<(), UploadError> {
-    let (notifier, notification_rx) = UploadNotifier::make();
-
-    let upload_fut = upload_video(client, downloader, url, initial_message, notifier)
-        .fuse()
-        .instrument(info_span!("upload_video"));
-    let upload_fut = timeout(video_handling_timeout, upload_fut);
+    items: &[JobItem],
+    cancels: &[CancellationToken],
+    job: &JobGuard,
+) -> Result<Vec<Result<(), UploadError>>, Whatever> {
+    let mut receivers = Vec::with_capacity(items.len());
+    let uploads = items
+        .iter()
+        .zip(cancels)
+        .map(|(item, cancel)| {
+            let (notifier, notification_rx) = UploadNotifier::make();
+            receivers.push((item.downloader.link_text(), notification_rx));
+            upload_item(context, initial_message, item, cancel.clone(), notifier)
+        })
+        .collect::<Vec<_>>();
+    let uploads_fut = futures::future::join_all(uploads).fuse();
+    let chat_id = Some(status_message.chat().id());
 
     let mut interval = tokio::time::interval(Duration::from_secs(1));
 
     let status_update_fut = async {
-        let mut magic = StatusMessageState::new(notification_rx);
+        let mut magic = StatusMessageState::new(receivers);
 
         loop {
             interval.tick().await;
-            if let Some(message) = magic.update() {
+            if let Some(text) = magic.update() {
                 debug!("Updating status message");
-                status_message
-                    .edit(message)
+                let sent = context
+                    .scheduler
+                    .run(chat_id, RequestKind::Droppable, || {
+                        status_message.edit(
+                            InputMessage::markdown(&text)
+                                .reply_markup(&callback::cancel_markup(job.id)),
+                        )
+                    })
                     .await
                     .whatever_context("Editing status message")?;
+                match sent {
+                    Some(()) => magic.mark_sent(text),
+                    // the next update will carry the most recent status anyway
+                    None => debug!("Status message update was dropped"),
+                }
             }
         }
 
@@ -91,59 +147,505 @@ pub async fn upload_with_status_updates(
     .instrument(info_span!("update_status_message"))
     .fuse();
     select! {
-        err = status_update_fut => return Err(err.unwrap_err()).context(OtherSnafu),
+        err = status_update_fut => Err(err.unwrap_err()),
+        results = uploads_fut => {
+            debug!("All the uploads finished");
+            Ok(results)
+        }
+    }
+}
+
+async fn upload_item(
+    context: &BotContext,
+    initial_message: &Message,
+    item: &JobItem,
+    cancel: CancellationToken,
+    notifier: UploadNotifier,
+) -> Result<(), UploadError> {
+    let finish_notifier = notifier.clone();
+    let deadline = Deadline::after(context.video_handling_timeout);
+
+    let upload_fut = upload_video(
+        context,
+        item.downloader.clone(),
+        item.url.clone(),
+        item.options.clone(),
+        item.output_mode,
+        initial_message,
+        notifier,
+        &deadline,
+    )
+    .instrument(info_span!(
+        "upload_video",
+        url = %item.url,
+        downloader_name = item.downloader.link_text()
+    ));
+    let upload_fut = deadline.limit(upload_fut);
+
+    // dropping the upload future abandons the partially uploaded file, telegram will clean it up eventually
+    let result = select! {
+        _ = cancel.cancelled() => {
+            debug!("Item was cancelled");
+            Err(UploadError::Cancelled)
+        }
         r = upload_fut => {
             debug!("Upload future finished");
             match r {
-                Ok(r) => return Ok(r.context(OtherSnafu)?),
-                Err(_) => return Err(UploadError::Timeout),
+                Some(r) => r.context(OtherSnafu),
+                None => Err(UploadError::Timeout),
             }
         }
+    };
+
+    // other items may still be in progress, so the status message has to show that this one is done
+    let _ = finish_notifier.notify_status(UploadStatus::Finished {
+        success: result.is_ok(),
+    });
+
+    result
+}
+
+/// The message with an uploaded video
+#[derive(Clone)]
+pub enum VideoMessage {
+    Regular(InputMessage),
+    /// grammers can't mark a document as animated, so the animations are sent with a raw request
+    Animation {
+        /// Markdown
+        caption: String,
+        media: tl::enums::InputMedia,
+    },
+}
+
+/// Sends the video to the chat, returning the sent message if it's known
+///
+/// The raw request for the animations only returns the updates, so their message isn't.
+pub async fn send_video_message(
+    context: &BotContext,
+    chat: PackedChat,
+    reply_to: Option<i32>,
+    message: &VideoMessage,
+) -> Result<Option<Message>, Whatever> {
+    match message {
+        VideoMessage::Regular(message) => context
+            .scheduler
+            .run_required(Some(chat.id), || {
+                context
+                    .client
+                    .send_message(chat, message.clone().reply_to(reply_to))
+            })
+            .await
+            .map(Some)
+            .whatever_context("Sending video message"),
+        VideoMessage::Animation { caption, media } => {
+            let (text, entities) = parse_markdown_message(caption);
+            let request = tl::functions::messages::SendMedia {
+                silent: false,
+                background: false,
+                clear_draft: false,
+                noforwards: false,
+                update_stickersets_order: false,
+                invert_media: false,
+                peer: chat.to_input_peer(),
+                reply_to: reply_to.map(|reply_to_msg_id| {
+                    tl::types::InputReplyToMessage {
+                        reply_to_msg_id,
+                        top_msg_id: None,
+                        reply_to_peer_id: None,
+                        quote_text: None,
+                        quote_entities: None,
+                        quote_offset: None,
+                    }
+                    .into()
+                }),
+                media: media.clone(),
+                message: text,
+                random_id: rand::random(),
+                reply_markup: None,
+                entities: (!entities.is_empty()).then_some(entities),
+                schedule_date: None,
+                send_as: None,
+                quick_reply_shortcut: None,
+                effect: None,
+            };
+            context
+                .scheduler
+                .run_required(Some(chat.id), || context.client.invoke(&request))
+                .await
+                .whatever_context("Sending animation message")?;
+            Ok(None)
+        }
     }
 }
 
-async fn upload_video(
-    bot: &Client,
+/// A video uploaded to telegram, but not sent anywhere yet
+pub struct PreparedVideo {
+    pub message: VideoMessage,
+    pub canonical_url: Url,
+    pub link_text: &'static str,
+    /// Subtitles to send next to the video, unless they were burned in
+    pub subtitles: Option<Subtitles>,
+}
+
+/// Telegram does not accept longer round video notes
+const ROUND_VIDEO_MAX_DURATION: Duration = Duration::from_secs(60);
+/// Re-encoding gets at least this much time, however short the video is
+const MIN_ENCODING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Re-encodes the video with the `deadline` paused, giving it the time proportional to the video duration instead
+async fn transcode_paused(
+    context: &BotContext,
+    deadline: &Deadline,
+    stream: BoxStream<'static, io::Result<Bytes>>,
+    duration: Duration,
+    encoding: Encoding,
+    burn_subtitles: Option<&str>,
+    notifier: &UploadNotifier,
+) -> Result<(BoxStream<'static, io::Result<Bytes>>, u64), Whatever> {
+    let limit = duration
+        .mul_f64(context.encoding_timeout_factor)
+        .max(MIN_ENCODING_TIMEOUT);
+    deadline
+        .pause_for(
+            limit,
+            transcode::transcode(stream, duration, encoding, burn_subtitles, notifier),
+        )
+        .await
+        .whatever_context("Re-encoding took too long")?
+}
+
+/// Downloads the video and uploads it to telegram, making a message that can be sent to any chat
+///
+/// Round video notes that are too long are sent as regular videos, with a note in the caption.
+/// The `deadline` is paused while the video is re-encoded.
+pub async fn prepare_video(
+    context: &BotContext,
     downloader: Arc<dyn Downloader>,
     url: Url,
-    initial_message: &Message,
+    options: DownloadOptions,
+    output_mode: OutputMode,
     notifier: UploadNotifier,
-) -> Result<(), Whatever> {
+    deadline: &Deadline,
+) -> Result<PreparedVideo, Whatever> {
     let link_text = downloader.link_text();
+    // the role of the user may have a lower limit
+    let size_limit = options
+        .max_size
+        .map_or(context.upload_size_limit, |max_size| {
+            max_size.min(context.upload_size_limit)
+        });
 
     let VideoDownloadResult {
         canonical_url,
         video_information,
+        metadata,
+        thumbnail_url,
+        subtitles,
         video_stream: BytesStream { stream, size },
-    } = downloader.download(url.clone(), notifier).await?;
-    let mut stream = stream.into_async_read().compat();
+    } = downloader
+        .download(
+            url.clone(),
+            DownloadOptions {
+                max_size: Some(size_limit),
+                ..options
+            },
+            notifier.clone(),
+        )
+        .await?;
+
+    // without the video information, telegram does not play big files as streaming videos
+    // the codecs are needed to decide whether to transcode
+    let (probed, mut stream) = if video_information.is_none() || context.transcoding.is_some() {
+        probe::probe_stream(stream)
+            .await
+            .whatever_context("Reading the video to probe it")?
+    } else {
+        (None, stream)
+    };
+    let mut size = size;
+
+    // when the video is re-encoded anyway, the subtitles are burned in instead of being sent separately
+    let mut subtitles = subtitles;
+    if let (Some(transcoding), Some(probed)) = (&context.transcoding, &probed) {
+        if transcode::needs_transcoding(transcoding, probed) || subtitles.is_some() {
+            info!(
+                "Transcoding the video with codecs {:?}/{:?}",
+                probed.video_codec, probed.audio_codec
+            );
+            (stream, size) = transcode_paused(
+                context,
+                deadline,
+                stream,
+                probed.video_information.duration,
+                Encoding::Compatible,
+                subtitles
+                    .take()
+                    .as_ref()
+                    .map(|subtitles| subtitles.srt.as_str()),
+                &notifier,
+            )
+            .await?;
+        }
+    }
+
+    // the video was not probed if the downloader provided the information
+    let mut video_information = video_information.or(probed.map(|probed| probed.video_information));
+
+    let mut notes = Vec::new();
+    let mut output_mode = output_mode;
+    match output_mode {
+        OutputMode::Video => {}
+        OutputMode::Round => match &mut video_information {
+            Some(info) if info.duration <= ROUND_VIDEO_MAX_DURATION => {
+                info!("Cropping the video into a round video note");
+                (stream, size) = transcode_paused(
+                    context,
+                    deadline,
+                    stream,
+                    info.duration,
+                    Encoding::Round,
+                    None,
+                    &notifier,
+                )
+                .await?;
+                let side = info.width.min(info.height).min(ROUND_VIDEO_SIZE);
+                (info.width, info.height) = (side, side);
+            }
+            Some(_) => {
+                info!("The video is too long for a round video note, sending a regular one");
+                notes.push(Lang::CaptionRoundTooLong.to_string());
+                output_mode = OutputMode::Video;
+            }
+            None => {
+                info!("The duration of the video is unknown, sending a regular one instead of a round video note");
+                notes.push(Lang::CaptionRoundUnknownDuration.to_string());
+                output_mode = OutputMode::Video;
+            }
+        },
+        OutputMode::Animation => {
+            let Some(duration) = video_information.as_ref().map(|info| info.duration) else {
+                whatever!("Can't make an animation without knowing the video duration");
+            };
+            info!("Removing the audio to send the video as an animation");
+            (stream, size) = transcode_paused(
+                context,
+                deadline,
+                stream,
+                duration,
+                Encoding::Silent,
+                None,
+                &notifier,
+            )
+            .await?;
+        }
+    }
+
+    if size > size_limit {
+        if !context.compress_oversized {
+            whatever!(
+                "The video is too large ({} bytes, the limit is {})",
+                size,
+                size_limit
+            );
+        }
+
+        let Some(duration) = video_information.as_ref().map(|info| info.duration) else {
+            whatever!("Can't compress the video without knowing its duration");
+        };
+        info!(
+            "Compressing the video of {} bytes to fit into {}",
+            size, size_limit
+        );
+        (stream, size) = transcode_paused(
+            context,
+            deadline,
+            stream,
+            duration,
+            Encoding::FitSize {
+                max_size: size_limit,
+            },
+            None,
+            &notifier,
+        )
+        .await?;
+        notes.push(Lang::CaptionCompressed.to_string());
+    }
+
+    let (thumbnail, stream) = thumbnail::make_thumbnail(thumbnail_url.as_ref(), stream)
+        .await
+        .whatever_context("Reading the start of the video")?;
 
     debug!("Uploading the stream to telegram...");
-    let uploaded_video = bot
-        .upload_stream(&mut stream, size as usize, "video.mp4".to_string())
+    let uploaded_video = resumable::upload_input_file(
+        &context.client,
+        &context.scheduler,
+        stream,
+        size as usize,
+        "video.mp4".to_string(),
+    )
+    .await
+    .whatever_context("Uploading video")?;
+
+    // round video notes can't have a caption
+    let caption = match output_mode {
+        OutputMode::Round => String::new(),
+        OutputMode::Video | OutputMode::Animation => caption::render_caption(
+            &context.captions.template,
+            &canonical_url,
+            link_text,
+            &metadata,
+            &notes,
+        ),
+    };
+    let thumbnail = match thumbnail {
+        Some(thumbnail) => {
+            let size = thumbnail.len();
+            let uploaded_thumbnail = resumable::upload_input_file(
+                &context.client,
+                &context.scheduler,
+                futures::stream::once(async { Ok(thumbnail) }).boxed(),
+                size,
+                "thumbnail.jpg".to_string(),
+            )
+            .await
+            .whatever_context("Uploading thumbnail")?;
+            Some(uploaded_thumbnail)
+        }
+        None => None,
+    };
+    // big files require this information
+    // short videos can be sent without it
+    let video_attribute = video_information.map(|video_information| Attribute::Video {
+        h: video_information.height,
+        w: video_information.width,
+        duration: video_information.duration,
+        round_message: output_mode == OutputMode::Round,
+        supports_streaming: output_mode == OutputMode::Video,
+    });
+
+    let message = match output_mode {
+        OutputMode::Video | OutputMode::Round => {
+            let mut message = InputMessage::markdown(caption)
+                .document(Uploaded::from_raw(uploaded_video.clone()));
+            if let Some(thumbnail) = thumbnail {
+                message = message.thumbnail(Uploaded::from_raw(thumbnail));
+            }
+            if let Some(video_attribute) = video_attribute {
+                message = message.attribute(video_attribute);
+            }
+            VideoMessage::Regular(message)
+        }
+        OutputMode::Animation => {
+            let mut attributes = vec![
+                tl::types::DocumentAttributeAnimated {}.into(),
+                tl::types::DocumentAttributeFilename {
+                    file_name: "video.mp4".to_string(),
+                }
+                .into(),
+            ];
+            attributes.extend(video_attribute.map(tl::enums::DocumentAttribute::from));
+            VideoMessage::Animation {
+                caption,
+                media: tl::types::InputMediaUploadedDocument {
+                    nosound_video: true,
+                    force_file: false,
+                    spoiler: false,
+                    file: uploaded_video,
+                    thumb: thumbnail,
+                    mime_type: "video/mp4".to_string(),
+                    attributes,
+                    stickers: None,
+                    ttl_seconds: None,
+                }
+                .into(),
+            }
+        }
+    };
+
+    Ok(PreparedVideo {
+        message,
+        canonical_url,
+        link_text,
+        subtitles,
+    })
+}
+
+/// Sends the subtitles as a document replying to the video
+async fn send_subtitles(
+    context: &BotContext,
+    video_message: &Message,
+    subtitles: &Subtitles,
+) -> Result<(), Whatever> {
+    let srt = Bytes::from(subtitles.srt.clone());
+    let size = srt.len();
+    let uploaded = resumable::upload_stream(
+        &context.client,
+        &context.scheduler,
+        futures::stream::once(async { Ok(srt) }).boxed(),
+        size,
+        format!("subtitles.{}.srt", subtitles.language),
+    )
+    .await
+    .whatever_context("Uploading subtitles")?;
+
+    let message =
+        InputMessage::from(Lang::SubtitlesCaption(subtitles.language.clone())).document(uploaded);
+    context
+        .scheduler
+        .run(
+            Some(video_message.chat().id()),
+            RequestKind::Required,
+            || video_message.reply(message.clone()),
+        )
         .await
-        .whatever_context("Uploading video")?;
+        .whatever_context("Sending subtitles")?;
+
+    Ok(())
+}
+
+async fn upload_video(
+    context: &BotContext,
+    downloader: Arc<dyn Downloader>,
+    url: Url,
+    options: DownloadOptions,
+    output_mode: OutputMode,
+    initial_message: &Message,
+    notifier: UploadNotifier,
+    deadline: &Deadline,
+) -> Result<(), Whatever> {
+    let video = prepare_video(
+        context,
+        downloader,
+        url.clone(),
+        options.clone(),
+        output_mode,
+        notifier,
+        deadline,
+    )
+    .await?;
 
     debug!("Sending the video message...");
-    let mut message = InputMessage::markdown(markdown::link(canonical_url.as_str(), &link_text))
-        .document(uploaded_video);
-    if let Some(video_information) = video_information {
-        // big files require this information
-        // short videos can be sent without it
-        // we only return this from youtube videos for now
-        message = message.attribute(Attribute::Video {
-            h: video_information.height,
-            w: video_information.width,
-            duration: video_information.duration,
-            round_message: false,
-            supports_streaming: true,
-        });
+    let sent_message = send_video_message(
+        context,
+        initial_message.chat().pack(),
+        Some(initial_message.id()),
+        &video.message,
+    )
+    .await?;
+
+    if let Some(subtitles) = &video.subtitles {
+        let reply_to = sent_message.as_ref().unwrap_or(initial_message);
+        send_subtitles(context, reply_to, subtitles).await?;
     }
 
-    initial_message
-        .reply(message)
-        .await
-        .whatever_context("Sending video message")?;
+    // only the videos downloaded with the default options can be shared through the inline mode
+    if let Some(sent_message) = sent_message.filter(|_| {
+        options.format_id.is_none()
+            && options.subtitles.is_none()
+            && output_mode == OutputMode::Video
+    }) {
+        inline::cache_upload(context, url, &video, &sent_message);
+    }
 
     debug!("Successfully sent video!");
 
@@ -152,17 +654,18 @@ async fn upload_video(
 
 struct StatusMessageState {
     magic_index: usize,
-    status_receiver: Receiver<UploadStatus>,
+    /// Link text of the downloader and the status of every item
+    status_receivers: Vec<(&'static str, Receiver<UploadStatus>)>,
     previous_text: Option<String>,
 }
 
 impl StatusMessageState {
     const MAGIC_PARTS: &'static [&'static str] = &[":｡", "･:*", ":･ﾟ", "’★,｡", "･:*", ":･ﾟ", "’☆"];
 
-    pub fn new(status_receiver: Receiver<UploadStatus>) -> Self {
+    pub fn new(status_receivers: Vec<(&'static str, Receiver<UploadStatus>)>) -> Self {
         Self {
             magic_index: 1,
-            status_receiver,
+            status_receivers,
             previous_text: None,
         }
     }
@@ -182,7 +685,29 @@ impl StatusMessageState {
         progressbar
     }
 
-    pub fn update(&mut self) -> Option<InputMessage> {
+    fn format_status(status: &UploadStatus) -> String {
+        match *status {
+            UploadStatus::FetchingLink => Lang::StatusGettingLink.to_string(),
+            UploadStatus::Uploading { progress } => {
+                markdown::code_inline(&Self::format_progress_bar(progress))
+            }
+            UploadStatus::Transcoding { progress } => format!(
+                "{}\n{}",
+                Lang::StatusTranscoding,
+                markdown::code_inline(&Self::format_progress_bar(progress))
+            ),
+            UploadStatus::Compressing { progress } => format!(
+                "{}\n{}",
+                Lang::StatusCompressing,
+                markdown::code_inline(&Self::format_progress_bar(progress))
+            ),
+            UploadStatus::Finished { success: true } => Lang::StatusItemDone.to_string(),
+            UploadStatus::Finished { success: false } => Lang::StatusItemFailed.to_string(),
+        }
+    }
+
+    /// Returns the new status message text, if it has changed since the last sent one.
+    pub fn update(&mut self) -> Option<String> {
         self.magic_index += 1;
         if self.magic_index == Self::MAGIC_PARTS.len() {
             self.magic_index = 0;
@@ -192,13 +717,24 @@ impl StatusMessageState {
         let magic = magic.join("");
         let message = format!("{} {}", Lang::StatusWorking, magic);
 
-        let status = self.status_receiver.borrow_and_update();
-
-        let body = match *status {
-            UploadStatus::FetchingLink => Lang::StatusGettingLink.to_string(),
-            UploadStatus::Uploading { progress } => {
-                markdown::code_inline(&Self::format_progress_bar(progress))
-            }
+        let total = self.status_receivers.len();
+        let body = if let [(_, status_receiver)] = self.status_receivers.as_mut_slice() {
+            Self::format_status(&status_receiver.borrow_and_update())
+        } else {
+            self.status_receivers
+                .iter_mut()
+                .enumerate()
+                .map(|(index, (link_text, status_receiver))| {
+                    format!(
+                        "{} – {}/{}\n{}",
+                        link_text,
+                        index + 1,
+                        total,
+                        Self::format_status(&status_receiver.borrow_and_update())
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n")
         };
 
         let message = format!("{}\n\n{}", message, body);
@@ -207,9 +743,12 @@ impl StatusMessageState {
                 return None;
             }
         }
-        let result = InputMessage::markdown(&message);
-        self.previous_text = Some(message);
 
-        Some(result)
+        Some(message)
+    }
+
+    /// Remembers the text that was actually sent, so that it won't be sent again
+    pub fn mark_sent(&mut self, text: String) {
+        self.previous_text = Some(text);
     }
 }
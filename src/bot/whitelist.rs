@@ -1,105 +1,224 @@
-use std::{collections::HashMap, io, path::PathBuf};
+use std::{collections::HashMap, sync::Arc};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use snafu::ResultExt;
-use tokio::fs::read_to_string;
+use serde_json::Value;
+use snafu::{whatever, ResultExt};
 
-use super::UserId;
-use crate::whatever::Whatever;
+use super::{roles::Role, UserId};
+use crate::{
+    state_store::{Collection, StateStore},
+    whatever::Whatever,
+};
+
+/// Version of the stored whitelist format, bumped on incompatible changes
+const SCHEMA_VERSION: u32 = 2;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Ord, PartialOrd, Clone, Hash)]
 pub struct UserInfo {
     pub access_hash: i64,
+    /// Missing in the files written before the roles were introduced
+    #[serde(default)]
+    pub role: Role,
+    /// Missing in the files written before the entries could expire, like the fields below
+    #[serde(default)]
+    pub added_at: Option<DateTime<Utc>>,
+    /// `None` if the user got in without anyone's involvement, or the entry is old
+    #[serde(default)]
+    pub added_by: Option<UserId>,
+    /// `None` if the access never expires
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl UserInfo {
+    /// A permanent entry with the default role, added now
+    pub fn new(access_hash: i64, added_by: Option<UserId>) -> Self {
+        Self {
+            access_hash,
+            role: Role::default(),
+            added_at: Some(Utc::now()),
+            added_by,
+            expires_at: None,
+        }
+    }
+
+    /// The same entry with a fresh access hash and expiration, still showing who added the user and when
+    pub fn renewed(&self, access_hash: i64, expires_at: Option<DateTime<Utc>>) -> Self {
+        Self {
+            access_hash,
+            expires_at,
+            ..self.clone()
+        }
+    }
+
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.map_or(true, |expires_at| expires_at > now)
+    }
+}
+
+/// The whitelist as it's kept in the state store
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredWhitelist {
+    Versioned {
+        version: u32,
+        users: HashMap<UserId, UserInfo>,
+    },
+    /// Just the users, written before the whitelist had a version (version 1)
+    Unversioned(HashMap<UserId, UserInfo>),
+}
+
+fn parse_whitelist(value: Value) -> Result<HashMap<UserId, UserInfo>, Whatever> {
+    match serde_json::from_value(value).whatever_context("Deserializing whitelist")? {
+        StoredWhitelist::Versioned { version, .. } if version > SCHEMA_VERSION => whatever!(
+            "The whitelist was written by a newer version of the bot (version {}, supported up to {})",
+            version,
+            SCHEMA_VERSION
+        ),
+        StoredWhitelist::Versioned { users, .. } | StoredWhitelist::Unversioned(users) => Ok(users),
+    }
 }
 
-/// Keeps track of allowed users storing updates on the disk.
+/// Keeps track of allowed users storing updates in the state store.
 pub struct Whitelist {
-    /// Path to the file to store state into.
-    storage_path: PathBuf,
+    store: Arc<dyn StateStore>,
     allowed_users: HashMap<UserId, UserInfo>,
 }
 
 impl Whitelist {
-    pub fn new_empty(path: PathBuf) -> Self {
+    pub fn new_empty(store: Arc<dyn StateStore>) -> Self {
         Self {
-            storage_path: path,
+            store,
             allowed_users: Default::default(),
         }
     }
 
-    /// Loads state from the storage
-    pub async fn new_from_disk(path: PathBuf) -> Result<Self, Whatever> {
-        let mut me = Self::new_empty(path);
-        match read_to_string(&me.storage_path).await {
-            Ok(data) => {
-                me.allowed_users = serde_json::from_str::<HashMap<UserId, UserInfo>>(&data)
-                    .whatever_context("Deserializing whitelist")?;
-            }
-            Err(e) => match e.kind() {
-                io::ErrorKind::NotFound => (),
-                _ => Err(e).whatever_context("Reading from file")?,
-            },
+    /// Loads state from the store
+    pub async fn load(store: Arc<dyn StateStore>) -> Result<Self, Whatever> {
+        let mut me = Self::new_empty(store);
+        if let Some(value) = me.store.load(Collection::Whitelist).await? {
+            me.allowed_users = parse_whitelist(value)?;
         }
         Ok(me)
     }
 
-    async fn store_into_disk(&mut self) -> Result<(), Whatever> {
-        let list_serialized =
-            serde_json::to_vec(&self.allowed_users).whatever_context("Serializing whitelist")?;
-        if let Some(parent) = self.storage_path.as_path().parent() {
-            tokio::fs::create_dir_all(parent)
-                .await
-                .whatever_context("Creating folder")?;
-        }
-        tokio::fs::write(self.storage_path.as_path(), list_serialized)
-            .await
-            .whatever_context("Writing to file")?;
-        Ok(())
+    async fn persist(&self) -> Result<(), Whatever> {
+        let stored = StoredWhitelist::Versioned {
+            version: SCHEMA_VERSION,
+            users: self.allowed_users.clone(),
+        };
+        let value = serde_json::to_value(&stored).whatever_context("Serializing whitelist")?;
+        self.store.store(Collection::Whitelist, &value).await
     }
 
     /// Adds a user to the whitelist.
     ///
     /// Returns whether the user was newly inserted. That is:
     ///
-    /// - If the set did not previously contain this value, `true` is returned,
-    ///     updates are stored in the disk.
+    /// - If the set did not previously contain this value (or it was expired), `true` is returned,
+    ///     updates are stored.
     /// - If the set already contained this value, `false` is returned.
     pub async fn insert(&mut self, user: UserId, info: UserInfo) -> Result<bool, Whatever> {
         let updated = self.allowed_users.insert(user, info.clone());
-        self.store_into_disk()
-            .await
-            .whatever_context("Storing state on disk")?;
-        Ok(updated.is_none())
+        self.persist().await.whatever_context("Storing state")?;
+        Ok(updated.map_or(true, |previous| !previous.is_active(Utc::now())))
     }
 
     /// Remove a user from the whitelist.
     ///
-    /// Returns removed access hash on success. Updates disk state if applicable.
+    /// Returns removed access hash on success. Updates the stored state if applicable.
     pub async fn remove(&mut self, user: UserId) -> Result<Option<UserInfo>, Whatever> {
         let updated = self.allowed_users.remove(&user);
         if updated.is_some() {
-            self.store_into_disk()
-                .await
-                .whatever_context("Storing state on disk")?;
+            self.persist().await.whatever_context("Storing state")?;
         }
         Ok(updated)
     }
 
-    /// Returns user info given user id.
+    /// Removes the expired entries.
+    ///
+    /// Returns the removed entries. Updates the stored state if applicable.
+    pub async fn prune_expired(&mut self) -> Result<Vec<(UserId, UserInfo)>, Whatever> {
+        let now = Utc::now();
+        let expired = self
+            .allowed_users
+            .iter()
+            .filter(|(_, info)| !info.is_active(now))
+            .map(|(user, info)| (*user, info.clone()))
+            .collect::<Vec<_>>();
+        if !expired.is_empty() {
+            for (user, _) in &expired {
+                self.allowed_users.remove(user);
+            }
+            self.persist().await.whatever_context("Storing state")?;
+        }
+        Ok(expired)
+    }
+
+    /// Changes the role of a user in the whitelist.
+    ///
+    /// Returns false if the user is not in the whitelist.
+    pub async fn set_role(&mut self, user: UserId, role: Role) -> Result<bool, Whatever> {
+        let Some(info) = self
+            .allowed_users
+            .get_mut(&user)
+            .filter(|info| info.is_active(Utc::now()))
+        else {
+            return Ok(false);
+        };
+        info.role = role;
+        self.persist().await.whatever_context("Storing state")?;
+        Ok(true)
+    }
+
+    /// Returns user info given user id, unless the entry is expired.
     #[inline]
-    #[allow(unused)]
     pub fn get(&self, user: &UserId) -> Option<&UserInfo> {
-        self.allowed_users.get(user)
+        self.allowed_users
+            .get(user)
+            .filter(|info| info.is_active(Utc::now()))
     }
 
-    /// Returns true if the list contains a user.
+    /// Returns true if the list contains a user whose entry is not expired.
     #[inline]
     pub fn contains(&self, user: &UserId) -> bool {
-        self.allowed_users.contains_key(user)
+        self.get(user).is_some()
     }
 
+    /// Returns the users whose entries are not expired.
     #[inline]
-    pub fn users(&self) -> &HashMap<UserId, UserInfo> {
-        &self.allowed_users
+    pub fn users(&self) -> impl Iterator<Item = (&UserId, &UserInfo)> {
+        let now = Utc::now();
+        self.allowed_users
+            .iter()
+            .filter(move |(_, info)| info.is_active(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn renewing_keeps_the_origin() {
+        let added_at = Utc::now() - Duration::days(30);
+        let info = UserInfo {
+            access_hash: 1,
+            role: Role::Power,
+            added_at: Some(added_at),
+            added_by: Some(UserId(2)),
+            expires_at: None,
+        };
+
+        let expires_at = Some(Utc::now() + Duration::days(7));
+        let renewed = info.renewed(3, expires_at);
+        assert_eq!(renewed.access_hash, 3);
+        assert_eq!(renewed.expires_at, expires_at);
+        assert_eq!(renewed.role, Role::Power);
+        assert_eq!(renewed.added_at, Some(added_at));
+        assert_eq!(renewed.added_by, Some(UserId(2)));
     }
 }
@@ -1,15 +1,53 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use serde::Deserialize;
 use snafu::ResultExt;
 
-use crate::{bot::UserId, whatever::Whatever};
+use crate::{
+    bot::{
+        roles::{PermissionsOverride, Role},
+        UserId,
+    },
+    whatever::Whatever,
+};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
     pub telegram: Telegram,
     pub data_storages: Data,
     pub access: Access,
+    /// Inline mode is disabled when not set
+    pub inline: Option<Inline>,
+    #[serde(default)]
+    pub captions: Captions,
+    /// Videos with codecs outside the allow-lists are sent as is when not set
+    pub transcoding: Option<Transcoding>,
+    /// Largest video the bot sends, in bytes
+    #[serde(default = "default_upload_size_limit")]
+    pub upload_size_limit: u64,
+    /// Re-encode the videos over `upload_size_limit` so that they fit, instead of failing
+    #[serde(default)]
+    pub compress_oversized: bool,
+    /// How long handling a single video may take, in seconds. The re-encoding is not counted
+    #[serde(default = "default_video_handling_timeout")]
+    pub video_handling_timeout: u64,
+    /// How long the re-encoding may take, in seconds per second of the video
+    #[serde(default = "default_encoding_timeout_factor")]
+    pub encoding_timeout_factor: f64,
+}
+
+/// Telegram limit for the bots
+fn default_upload_size_limit() -> u64 {
+    2000 * 1024 * 1024
+}
+
+fn default_video_handling_timeout() -> u64 {
+    60
+}
+
+/// ffmpeg is usually faster than that with `veryfast`, even on a modest CPU
+fn default_encoding_timeout_factor() -> f64 {
+    3.0
 }
 
 impl Config {
@@ -71,9 +109,108 @@ pub enum TelegramAccount {
 }
 #[derive(Deserialize, Clone, Debug)]
 pub struct Data {
+    /// Used by the JSON store, and imported from by the SQLite store if it has no whitelist yet.
+    /// The state without a file of its own is kept next to it
     pub whitelist_file: String,
+    /// Like [Self::whitelist_file], for the invites. `invites.json` next to the whitelist when not set
+    #[serde(default)]
+    pub invites_file: Option<String>,
+    /// Where the state is kept, the JSON files when not set
+    #[serde(default)]
+    pub state_store: StateStoreKind,
+}
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(tag = "type")]
+pub enum StateStoreKind {
+    #[default]
+    Json,
+    Sqlite {
+        path: String,
+    },
 }
 #[derive(Deserialize, Clone, Debug)]
 pub struct Access {
     pub superusers: HashSet<UserId>,
+    /// Group chats the bot is allowed to work in. Messages from other groups are ignored
+    #[serde(default)]
+    pub groups: Vec<GroupPolicy>,
+    /// Overrides the permissions of the roles, the ones not listed keep the defaults
+    #[serde(default)]
+    pub roles: HashMap<Role, PermissionsOverride>,
+    /// Chats and channels whose members count as whitelisted
+    #[serde(default)]
+    pub access_groups: Vec<AccessGroup>,
+    /// Remove the expired whitelist entries and tell the users they lost access.
+    /// Otherwise, the expired entries are just ignored
+    #[serde(default)]
+    pub prune_expired_users: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AccessGroup {
+    /// Username of the supergroup or channel, or its id without the `-100` prefix.
+    /// The bot has to be an admin there to see the members
+    pub chat: String,
+    /// Role of the members who are not whitelisted themselves
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupTrigger {
+    /// React to any message with a supported link, as well as to mentions and replies
+    Links,
+    /// React only when the bot is mentioned or replied to
+    Mention,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct GroupPolicy {
+    /// Id of the group as reported by telegram (without the `-100` prefix for supergroups and channels)
+    pub chat_id: i64,
+    pub trigger: GroupTrigger,
+    /// Let all the members of the group use the bot, not only the whitelisted ones
+    #[serde(default)]
+    pub allow_all_members: bool,
+}
+#[derive(Deserialize, Clone, Debug)]
+pub struct Inline {
+    /// Username of the chat the videos are uploaded to before being offered as inline results
+    pub staging_chat: String,
+}
+#[derive(Deserialize, Clone, Debug)]
+pub struct Captions {
+    /// Template of the sent video caption, in markdown
+    ///
+    /// Available placeholders: `{link}`, `{title}`, `{uploader}`, `{upload_date}`, `{duration}`, `{views}`.
+    /// Lines with placeholders the downloader did not provide a value for are left out.
+    pub template: String,
+}
+
+impl Default for Captions {
+    fn default() -> Self {
+        Self {
+            template: "{title}\n👤 {uploader}\n📅 {upload_date}\n⏱ {duration}\n👀 {views}\n{link}"
+                .to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Transcoding {
+    /// Video codecs (as named by ffprobe) that are sent as is, the others are re-encoded to H.264
+    #[serde(default = "default_allowed_video_codecs")]
+    pub allowed_video_codecs: Vec<String>,
+    /// Audio codecs (as named by ffprobe) that are sent as is, the others are re-encoded to AAC
+    #[serde(default = "default_allowed_audio_codecs")]
+    pub allowed_audio_codecs: Vec<String>,
+}
+
+fn default_allowed_video_codecs() -> Vec<String> {
+    vec!["h264".to_string()]
+}
+
+fn default_allowed_audio_codecs() -> Vec<String> {
+    vec!["aac".to_string(), "mp3".to_string()]
 }
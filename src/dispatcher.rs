@@ -13,7 +13,36 @@ impl DownloadDispatcher {
         Self { downloaders }
     }
 
-    pub fn find_downloader(&self, url: &Url) -> Option<Arc<dyn Downloader>> {
-        self.downloaders.iter().find(|d| d.probe_url(url)).cloned()
+    /// Finds a downloader supporting the url that comes after `previous` in the list, wrapping around.
+    ///
+    /// Returns `None` if `previous` is the only one supporting it.
+    pub fn find_next_downloader(
+        &self,
+        url: &Url,
+        previous: &Arc<dyn Downloader>,
+    ) -> Option<Arc<dyn Downloader>> {
+        self.downloaders_for(url, Some(previous)).next().cloned()
+    }
+
+    /// Downloaders supporting the url, in order
+    ///
+    /// With `after`, starts from the one after it, wrapping around and leaving it out.
+    /// Nothing is returned if `after` is not one of the downloaders.
+    pub fn downloaders_for<'a>(
+        &'a self,
+        url: &'a Url,
+        after: Option<&Arc<dyn Downloader>>,
+    ) -> impl Iterator<Item = &'a Arc<dyn Downloader>> + 'a {
+        let (first, second) = match after {
+            None => (&self.downloaders[..], &[][..]),
+            Some(after) => match self.downloaders.iter().position(|d| Arc::ptr_eq(d, after)) {
+                Some(position) => (
+                    &self.downloaders[position + 1..],
+                    &self.downloaders[..position],
+                ),
+                None => (&[][..], &[][..]),
+            },
+        };
+        first.iter().chain(second).filter(move |d| d.probe_url(url))
     }
 }
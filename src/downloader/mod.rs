@@ -1,3 +1,4 @@
+pub mod subtitles;
 pub mod tiktok;
 pub mod youtube;
 
@@ -12,6 +13,7 @@ use std::{
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use chrono::NaiveDate;
 use futures::{stream::BoxStream, Stream};
 use pin_project_lite::pin_project;
 use reqwest::Client;
@@ -36,19 +38,84 @@ pub struct BytesStream {
     pub size: u64,
 }
 
+/// Descriptive information about the video, shown in the caption
+///
+/// Every field is optional, downloaders fill in what the platform provides.
+#[derive(Debug, Clone, Default)]
+pub struct VideoMetadata {
+    pub title: Option<String>,
+    pub uploader: Option<String>,
+    pub upload_date: Option<NaiveDate>,
+    pub duration: Option<Duration>,
+    pub view_count: Option<u64>,
+}
+
 pub struct VideoDownloadResult {
     pub canonical_url: Url,
     pub video_information: Option<VideoInformation>,
+    pub metadata: VideoMetadata,
+    /// Preview image provided by the platform, if any
+    pub thumbnail_url: Option<Url>,
+    /// Only filled in when requested with [DownloadOptions::subtitles] and available
+    pub subtitles: Option<Subtitles>,
     pub video_stream: BytesStream,
 }
 
+/// A format the user can choose to download
+#[derive(Debug, Clone)]
+pub struct FormatInfo {
+    /// Downloader-specific identifier, passed back in [`DownloadOptions::format_id`]
+    pub id: String,
+    /// Human-readable description, like `720p`
+    pub label: String,
+    /// Estimated size in bytes, if known
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// The format chosen by the user, if any. Otherwise, the downloader picks one by itself
+    pub format_id: Option<String>,
+    /// When picking the format by itself, the downloader should prefer the ones smaller than this (in bytes)
+    ///
+    /// The bot also won't send larger videos, on top of the global upload size limit.
+    pub max_size: Option<u64>,
+    /// Fetch the subtitles too, if the downloader supports it
+    pub subtitles: Option<SubtitlesRequest>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SubtitlesRequest {
+    /// Language code, like `en`. The default language of the downloader if not set
+    pub language: Option<String>,
+}
+
+/// Subtitles of the video, ready to be sent
+#[derive(Debug, Clone)]
+pub struct Subtitles {
+    pub language: String,
+    /// Contents of an SRT file
+    pub srt: String,
+}
+
 #[async_trait]
 pub trait Downloader: Debug + Send + Sync {
     fn probe_url(&self, url: &Url) -> bool;
+    /// Short name to refer to the downloader in the config
+    fn name(&self) -> &'static str;
     fn link_text(&self) -> &'static str;
+
+    /// Lists the formats available for the url.
+    ///
+    /// Downloaders that do not support choosing the format return an empty list.
+    async fn list_formats(self: Arc<Self>, _url: Url) -> Result<Vec<FormatInfo>, Whatever> {
+        Ok(Vec::new())
+    }
+
     async fn download(
         self: Arc<Self>,
         url: Url,
+        options: DownloadOptions,
         notifier: UploadNotifier,
     ) -> Result<VideoDownloadResult, Whatever>;
 }
@@ -0,0 +1,161 @@
+//! Converts the YouTube timed-text captions to SRT
+//!
+//! The timed-text XML looks like this:
+//!
+//! ```xml
+//! <transcript>
+//!   <text start="0.5" dur="2.25">Hello &amp;amp; welcome</text>
+//! </transcript>
+//! ```
+
+use std::{fmt::Write, time::Duration};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use snafu::{ensure_whatever, ResultExt};
+
+use crate::whatever::Whatever;
+
+static TEXT_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?s)<text start="([0-9.]+)"(?: dur="([0-9.]+)")?[^>]*>(.*?)</text>"#).unwrap()
+});
+
+/// How long a caption without the `dur` attribute is shown
+const DEFAULT_CAPTION_DURATION: Duration = Duration::from_secs(2);
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        // must go last, otherwise `&amp;lt;` would turn into `<`
+        .replace("&amp;", "&")
+}
+
+fn format_timestamp(timestamp: Duration) -> String {
+    let millis = timestamp.as_millis();
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+fn parse_seconds(seconds: &str) -> Result<Duration, Whatever> {
+    let seconds = seconds
+        .parse::<f64>()
+        .whatever_context("Parsing the caption timing")?;
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+/// Converts the timed-text XML to SRT
+pub fn timed_text_to_srt(xml: &str) -> Result<String, Whatever> {
+    let mut srt = String::new();
+    let mut index = 0;
+
+    for captures in TEXT_PATTERN.captures_iter(xml) {
+        let start = parse_seconds(&captures[1])?;
+        let duration = match captures.get(2) {
+            Some(duration) => parse_seconds(duration.as_str())?,
+            None => DEFAULT_CAPTION_DURATION,
+        };
+        // YouTube escapes the text twice
+        let text = unescape_xml(&unescape_xml(&captures[3]));
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        index += 1;
+        writeln!(
+            srt,
+            "{}\n{} --> {}\n{}\n",
+            index,
+            format_timestamp(start),
+            format_timestamp(start + duration),
+            text
+        )
+        .unwrap();
+    }
+
+    ensure_whatever!(index > 0, "The captions are empty");
+    Ok(srt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACK: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<transcript>
+  <text start="0.5" dur="2.25">Hello there</text>
+  <text start="3" dur="1.5">second
+line</text>
+  <text start="3661.25" dur="0.75">much later</text>
+</transcript>"#;
+
+    #[test]
+    fn converts_a_track() {
+        assert_eq!(
+            timed_text_to_srt(TRACK).unwrap(),
+            "1\n00:00:00,500 --> 00:00:02,750\nHello there\n\n\
+             2\n00:00:03,000 --> 00:00:04,500\nsecond\nline\n\n\
+             3\n01:01:01,250 --> 01:01:02,000\nmuch later\n\n"
+        );
+    }
+
+    #[test]
+    fn uses_the_default_duration_without_dur() {
+        let xml = r#"<transcript><text start="1.25">no duration</text></transcript>"#;
+        assert_eq!(
+            timed_text_to_srt(xml).unwrap(),
+            "1\n00:00:01,250 --> 00:00:03,250\nno duration\n\n"
+        );
+        assert_eq!(DEFAULT_CAPTION_DURATION, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn skips_blank_captions() {
+        let xml = r#"<transcript>
+  <text start="0" dur="1">  </text>
+  <text start="1" dur="1">text</text>
+</transcript>"#;
+        assert_eq!(
+            timed_text_to_srt(xml).unwrap(),
+            "1\n00:00:01,000 --> 00:00:02,000\ntext\n\n"
+        );
+    }
+
+    #[test]
+    fn fails_on_an_empty_track() {
+        assert!(timed_text_to_srt("<transcript></transcript>").is_err());
+        assert!(
+            timed_text_to_srt(r#"<transcript><text start="0" dur="1"></text></transcript>"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn unescapes_entities_twice() {
+        let xml = r#"<transcript><text start="0" dur="1">Tom &amp;amp; Jerry &amp;quot;&amp;#39;&amp;gt;&amp;lt;&amp;quot;</text></transcript>"#;
+        assert_eq!(
+            timed_text_to_srt(xml).unwrap(),
+            "1\n00:00:00,000 --> 00:00:01,000\nTom & Jerry \"'><\"\n\n"
+        );
+    }
+
+    #[test]
+    fn does_not_over_unescape() {
+        // the caption literally says `&lt;3`, YouTube escapes it twice
+        let xml =
+            r#"<transcript><text start="0" dur="1">I &amp;amp;lt;3 captions</text></transcript>"#;
+        assert_eq!(
+            timed_text_to_srt(xml).unwrap(),
+            "1\n00:00:00,000 --> 00:00:01,000\nI &lt;3 captions\n\n"
+        );
+        assert_eq!(unescape_xml("&amp;lt;"), "&lt;");
+    }
+}
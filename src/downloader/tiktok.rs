@@ -9,13 +9,14 @@ use reqwest::{
     redirect::Policy,
     Client, ClientBuilder,
 };
+use serde::Deserialize;
 use snafu::{whatever, FromString, OptionExt, ResultExt};
-use tracing::debug;
+use tracing::{debug, warn};
 use url::Url;
 
 use crate::{
     bot::UploadNotifier,
-    downloader::{Downloader, VideoDownloadResult},
+    downloader::{DownloadOptions, Downloader, VideoDownloadResult, VideoMetadata},
     whatever::Whatever,
 };
 
@@ -134,6 +135,46 @@ async fn ttdownloader_get_video_link(client: &Client, tt_url: &Url) -> Result<Ur
     Ok(video_url)
 }
 
+/// The part of the TikTok oEmbed response we care about
+///
+/// See https://developers.tiktok.com/doc/embed-videos
+#[derive(Debug, Deserialize)]
+struct OEmbed {
+    title: Option<String>,
+    author_name: Option<String>,
+    /// The video cover
+    thumbnail_url: Option<String>,
+}
+
+impl From<OEmbed> for VideoMetadata {
+    fn from(oembed: OEmbed) -> Self {
+        Self {
+            title: oembed.title.filter(|t| !t.is_empty()),
+            uploader: oembed.author_name.filter(|n| !n.is_empty()),
+            ..Default::default()
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_oembed(client: &Client, tt_url: &Url) -> Result<OEmbed, Whatever> {
+    let mut oembed_url = Url::parse("https://www.tiktok.com/oembed").unwrap();
+    oembed_url
+        .query_pairs_mut()
+        .append_pair("url", tt_url.as_str());
+
+    client
+        .get(oembed_url)
+        .send()
+        .await
+        .whatever_context("Sending the oEmbed request")?
+        .error_for_status()
+        .whatever_context("TikTok responded with an error")?
+        .json()
+        .await
+        .whatever_context("Parsing the oEmbed response")
+}
+
 /// Downloads TikTok videos
 ///
 /// It's implemented by scraping https://ttdownloader.com
@@ -163,6 +204,10 @@ impl Downloader for TikTokDownloader {
             .any(|pattern| pattern.is_match(url.as_str()))
     }
 
+    fn name(&self) -> &'static str {
+        "tiktok"
+    }
+
     fn link_text(&self) -> &'static str {
         "🔗 TikTok"
     }
@@ -171,15 +216,38 @@ impl Downloader for TikTokDownloader {
     async fn download(
         self: Arc<Self>,
         url: Url,
+        _options: DownloadOptions,
         notifier: UploadNotifier,
     ) -> Result<VideoDownloadResult, Whatever> {
         let video_link = ttdownloader_get_video_link(&self.client, &url).await?;
+
+        // the metadata is nice to have, but not worth failing the download over
+        let (metadata, thumbnail_url) = match get_oembed(&self.client, &url).await {
+            Ok(mut oembed) => {
+                let thumbnail_url = oembed
+                    .thumbnail_url
+                    .take()
+                    .and_then(|thumbnail_url| Url::parse(&thumbnail_url).ok());
+                (oembed.into(), thumbnail_url)
+            }
+            Err(e) => {
+                warn!(
+                    "Could not get the video metadata: {}",
+                    snafu::Report::from_error(e)
+                );
+                (VideoMetadata::default(), None)
+            }
+        };
+
         let video_stream = super::stream_url(&self.client, video_link, notifier).await?;
 
         Ok(VideoDownloadResult {
-            // TODO: resolve the url, get the video information somehow
+            // TODO: resolve the url; the video information is probed by the bot
             canonical_url: url,
             video_information: None,
+            metadata,
+            thumbnail_url,
+            subtitles: None,
             video_stream,
         })
     }
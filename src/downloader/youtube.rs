@@ -1,13 +1,18 @@
 use std::{sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use snafu::ResultExt;
-use tracing::debug;
+use chrono::NaiveDate;
+use rusty_ytdl::{VideoFormat, VideoInfo};
+use snafu::{whatever, OptionExt, ResultExt};
+use tracing::{debug, warn};
 use url::Url;
 
 use crate::{
     bot::UploadNotifier,
-    downloader::{Downloader, VideoDownloadResult, VideoInformation},
+    downloader::{
+        subtitles, DownloadOptions, Downloader, FormatInfo, Subtitles, SubtitlesRequest,
+        VideoDownloadResult, VideoInformation, VideoMetadata,
+    },
     whatever::Whatever,
 };
 
@@ -20,20 +25,110 @@ impl YoutubeDownloader {
     }
 }
 
+/// Used when the user did not ask for a specific language
+const DEFAULT_SUBTITLES_LANGUAGE: &str = "en";
+
+/// Fetches the caption track in the requested language, falling back to the auto-generated one
+#[tracing::instrument(skip(client))]
+async fn fetch_subtitles(
+    client: &reqwest::Client,
+    video_id: &str,
+    request: &SubtitlesRequest,
+) -> Result<Subtitles, Whatever> {
+    let language = request
+        .language
+        .as_deref()
+        .unwrap_or(DEFAULT_SUBTITLES_LANGUAGE);
+
+    // `asr` is the kind of the auto-generated tracks
+    for kind in [None, Some("asr")] {
+        let mut url = Url::parse("https://www.youtube.com/api/timedtext").unwrap();
+        url.query_pairs_mut()
+            .append_pair("v", video_id)
+            .append_pair("lang", language);
+        if let Some(kind) = kind {
+            url.query_pairs_mut().append_pair("kind", kind);
+        }
+
+        let xml = client
+            .get(url)
+            .send()
+            .await
+            .whatever_context("Requesting the captions")?
+            .error_for_status()
+            .whatever_context("YouTube responded with an error")?
+            .text()
+            .await
+            .whatever_context("Reading the captions")?;
+        // missing tracks are returned as empty responses
+        if xml.trim().is_empty() {
+            debug!("No {:?} captions track for {}", kind, language);
+            continue;
+        }
+
+        return Ok(Subtitles {
+            language: language.to_string(),
+            srt: subtitles::timed_text_to_srt(&xml)?,
+        });
+    }
+
+    whatever!("No captions in {} for this video", language)
+}
+
+/// Formats that have both video and audio (so they can be sent without muxing), sorted by height
+fn progressive_formats(info: &VideoInfo) -> Vec<&VideoFormat> {
+    // rusty_ytdl's format selection algo is kinda whacky...
+    let mut formats = info
+        .formats
+        .iter()
+        .filter(|f| f.has_video && f.has_audio && f.height.is_some())
+        .collect::<Vec<_>>();
+    formats.sort_by_key(|f| f.height);
+    formats
+}
+
 #[async_trait]
 impl Downloader for YoutubeDownloader {
     fn probe_url(&self, url: &Url) -> bool {
         rusty_ytdl::get_video_id(url.as_str()).is_some()
     }
 
+    fn name(&self) -> &'static str {
+        "youtube"
+    }
+
     fn link_text(&self) -> &'static str {
         "🔗 YouTube"
     }
 
+    #[tracing::instrument]
+    async fn list_formats(self: Arc<Self>, url: Url) -> Result<Vec<FormatInfo>, Whatever> {
+        let video = rusty_ytdl::Video::new(url).whatever_context("Creating video")?;
+
+        let info = video
+            .get_info()
+            .await
+            .whatever_context("Getting video info")?;
+
+        Ok(progressive_formats(&info)
+            .into_iter()
+            .rev()
+            .map(|f| FormatInfo {
+                id: f.itag.to_string(),
+                label: f
+                    .quality_label
+                    .clone()
+                    .unwrap_or_else(|| format!("{}p", f.height.unwrap())),
+                size: f.content_length.as_ref().and_then(|l| l.parse().ok()),
+            })
+            .collect())
+    }
+
     #[tracing::instrument(skip(notifier))]
     async fn download(
         self: Arc<Self>,
         url: Url,
+        options: DownloadOptions,
         notifier: UploadNotifier,
     ) -> Result<VideoDownloadResult, Whatever> {
         debug!("Starting download!");
@@ -51,14 +146,27 @@ impl Downloader for YoutubeDownloader {
             .whatever_context("Getting video info")?;
         debug!("Got video info: {:?}", info);
 
-        // rusty_ytdl's format selection algo is kinda whacky...
-        let mut formats = info
-            .formats
-            .iter()
-            .filter(|f| f.has_video && f.has_audio && f.height.is_some())
-            .collect::<Vec<_>>();
-        formats.sort_by_key(|f| f.height);
-        let format = formats.last().unwrap();
+        let formats = progressive_formats(&info);
+        let format = match &options.format_id {
+            Some(format_id) => formats
+                .iter()
+                .find(|f| &f.itag.to_string() == format_id)
+                .whatever_context("The chosen format is not available anymore")?,
+            // the tallest one that fits, or just the tallest one if none does (the bot may compress it then)
+            None => formats
+                .iter()
+                .rev()
+                .find(|f| match options.max_size {
+                    None => true,
+                    Some(max_size) => f
+                        .content_length
+                        .as_ref()
+                        .and_then(|l| l.parse::<u64>().ok())
+                        .map_or(false, |size| size <= max_size),
+                })
+                .or(formats.last())
+                .whatever_context("No formats with both video and audio")?,
+        };
 
         debug!("Chosen format: {:?}", format);
 
@@ -79,11 +187,48 @@ impl Downloader for YoutubeDownloader {
             ),
         };
 
+        let details = &info.video_details;
+        let metadata = VideoMetadata {
+            title: Some(details.title.clone()).filter(|t| !t.is_empty()),
+            uploader: Some(details.owner_channel_name.clone()).filter(|n| !n.is_empty()),
+            // the date may be followed by the time, like `2020-01-20T04:00:00-08:00`
+            upload_date: details
+                .publish_date
+                .get(..10)
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()),
+            duration: Some(video_information.duration),
+            view_count: details.view_count.parse().ok(),
+        };
+
+        let thumbnail_url = details
+            .thumbnails
+            .iter()
+            .max_by_key(|thumbnail| thumbnail.width)
+            .and_then(|thumbnail| Url::parse(&thumbnail.url).ok());
+
+        // the subtitles are nice to have, but not worth failing the download over
+        let subtitles = match &options.subtitles {
+            Some(request) => match fetch_subtitles(&client, &details.video_id, request).await {
+                Ok(subtitles) => Some(subtitles),
+                Err(e) => {
+                    warn!(
+                        "Could not get the subtitles: {}",
+                        snafu::Report::from_error(e)
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
         let video_stream = super::stream_url(&client, stream_url, notifier).await?;
 
         Ok(VideoDownloadResult {
             canonical_url: Url::parse(&video.get_video_url()).unwrap(),
             video_information: Some(video_information),
+            metadata,
+            thumbnail_url,
+            subtitles,
             video_stream,
         })
 
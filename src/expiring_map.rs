@@ -0,0 +1,145 @@
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// A map that forgets its entries after a fixed time-to-live
+///
+/// Expired entries are not returned and are cleaned up on insertion.
+pub struct ExpiringMap<K, V> {
+    ttl: Duration,
+    entries: HashMap<K, (Instant, V)>,
+}
+
+impl<K: Hash + Eq, V> ExpiringMap<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.insert_at(key, value, Instant::now())
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_at(key, Instant::now())
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_mut_at(key, Instant::now())
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_at(key, Instant::now())
+    }
+
+    // the `_at` variants take the current time, so that the tests don't depend on the real clock
+
+    fn insert_at(&mut self, key: K, value: V, now: Instant) {
+        self.entries.retain(|_, (expires_at, _)| *expires_at > now);
+        self.entries.insert(key, (now + self.ttl, value));
+    }
+
+    fn get_at<Q>(&self, key: &Q, now: Instant) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries
+            .get(key)
+            .filter(|(expires_at, _)| *expires_at > now)
+            .map(|(_, value)| value)
+    }
+
+    fn get_mut_at<Q>(&mut self, key: &Q, now: Instant) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries
+            .get_mut(key)
+            .filter(|(expires_at, _)| *expires_at > now)
+            .map(|(_, value)| value)
+    }
+
+    fn remove_at<Q>(&mut self, key: &Q, now: Instant) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries
+            .remove(key)
+            .filter(|(expires_at, _)| *expires_at > now)
+            .map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn returns_entries_before_they_expire() {
+        let start = Instant::now();
+        let mut map = ExpiringMap::new(TTL);
+        map.insert_at("a", 1, start);
+
+        let now = start + TTL - Duration::from_millis(1);
+        assert_eq!(map.get_at("a", now), Some(&1));
+        *map.get_mut_at("a", now).unwrap() += 1;
+        assert_eq!(map.remove_at("a", now), Some(2));
+        assert_eq!(map.get_at("a", now), None);
+    }
+
+    #[test]
+    fn forgets_expired_entries() {
+        let start = Instant::now();
+        let mut map = ExpiringMap::new(TTL);
+        map.insert_at("a", 1, start);
+
+        let now = start + TTL;
+        assert_eq!(map.get_at("a", now), None);
+        assert!(map.get_mut_at("a", now).is_none());
+        assert_eq!(map.remove_at("a", now), None);
+    }
+
+    #[test]
+    fn cleans_up_on_insertion() {
+        let start = Instant::now();
+        let mut map = ExpiringMap::new(TTL);
+        map.insert_at("a", 1, start);
+        map.insert_at("b", 2, start + TTL * 2);
+
+        assert_eq!(map.entries.len(), 1);
+        assert_eq!(map.get_at("b", start + TTL * 2), Some(&2));
+    }
+
+    #[test]
+    fn reinsertion_restarts_the_ttl() {
+        let start = Instant::now();
+        let mut map = ExpiringMap::new(TTL);
+        map.insert_at("a", 1, start);
+        map.insert_at("a", 2, start + TTL / 2);
+
+        assert_eq!(map.get_at("a", start + TTL), Some(&2));
+        assert_eq!(map.get_at("a", start + TTL * 3 / 2), None);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::{sync::Arc, time::Duration};
 
 use futures::{StreamExt, TryStreamExt};
 use snafu::ResultExt as _;
@@ -6,18 +6,22 @@ use tokio::sync::Mutex;
 use tracing::{error, info};
 
 use crate::{
-    bot::whitelist::Whitelist,
+    bot::{invites::Invites, whitelist::Whitelist},
     dispatcher::DownloadDispatcher,
     downloader::{tiktok::TikTokDownloader, youtube::YoutubeDownloader},
     whatever::Whatever,
 };
 
+mod atomic_file;
 mod bot;
 mod config;
 mod dispatcher;
 mod downloader;
+mod expiring_map;
 mod grammers_boilerplate;
 mod init_tracing;
+mod media;
+mod state_store;
 mod whatever;
 
 // #[allow(unused)]
@@ -73,16 +77,26 @@ async fn main() -> Result<(), Whatever> {
 
     let client = grammers_boilerplate::connect_and_login(&config.telegram).await?;
 
-    info!("Loading whitelist from disk");
-    let whitelist = Whitelist::new_from_disk(PathBuf::from(config.data_storages.whitelist_file))
+    let state_store = state_store::open(&config.data_storages)
+        .await
+        .whatever_context("Opening the state store has failed")?;
+
+    info!("Loading whitelist");
+    let whitelist = Whitelist::load(state_store.clone())
         .await
         .whatever_context("Loading whitelist has failed")?;
     info!(
         "Resolved whitelist with {} entries",
-        whitelist.users().len()
+        whitelist.users().count()
     );
     let whitelist = Arc::new(Mutex::new(whitelist));
 
+    info!("Loading invites");
+    let invites = Invites::load(state_store.clone())
+        .await
+        .whatever_context("Loading invites has failed")?;
+    let invites = Arc::new(Mutex::new(invites));
+
     let dispatcher = DownloadDispatcher::new(vec![
         Arc::new(YoutubeDownloader::new()),
         Arc::new(TikTokDownloader::new()),
@@ -93,7 +107,7 @@ async fn main() -> Result<(), Whatever> {
         _ = tokio::signal::ctrl_c() => {
             info!("Got SIGINT; quitting early gracefully");
         }
-        r = bot::run_bot(&client, dispatcher, Duration::from_secs(60), whitelist, config.access.superusers) => {
+        r = bot::run_bot(&client, dispatcher, Duration::from_secs(config.video_handling_timeout), whitelist, invites, state_store, config.clone()) => {
             match r {
                 Ok(_) => info!("Got disconnected from Telegram gracefully"),
                 Err(e) => error!("Error during update handling: {}", e),
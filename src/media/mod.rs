@@ -0,0 +1,241 @@
+//! Video processing with ffmpeg
+//!
+//! ffmpeg is expected to be available in `PATH`.
+
+pub mod probe;
+pub mod thumbnail;
+pub mod transcode;
+
+use std::{collections::HashMap, io, path::PathBuf, process::Stdio, time::Duration};
+
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use snafu::{whatever, FromString, ResultExt};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    pin,
+    process::Command,
+};
+use tokio_util::io::ReaderStream;
+use tracing::{trace, warn};
+
+use crate::whatever::Whatever;
+
+const FFMPEG: &str = "ffmpeg";
+const FFPROBE: &str = "ffprobe";
+
+/// Runs ffmpeg with `input` fed to its stdin, returning its stdout
+pub async fn run_ffmpeg(args: &[&str], input: Bytes) -> Result<Vec<u8>, Whatever> {
+    run_tool(FFMPEG, args, input).await
+}
+
+/// Runs ffprobe with `input` fed to its stdin, returning its stdout
+pub async fn run_ffprobe(args: &[&str], input: Bytes) -> Result<Vec<u8>, Whatever> {
+    run_tool(FFPROBE, args, input).await
+}
+
+async fn run_tool(program: &str, args: &[&str], input: Bytes) -> Result<Vec<u8>, Whatever> {
+    let mut child = Command::new(program)
+        .args(["-hide_banner", "-loglevel", "error"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_whatever_context(|_| format!("Starting {}", program))?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let write_input = async move {
+        // ffmpeg may stop reading as soon as it has enough data, so a broken pipe is fine
+        let _ = stdin.write_all(&input).await;
+    };
+
+    let (_, output) = tokio::join!(write_input, child.wait_with_output());
+    let output = output.with_whatever_context(|_| format!("Waiting for {}", program))?;
+    if !output.status.success() {
+        whatever!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(output.stdout)
+}
+
+/// Reads the start of the stream (at least `limit` bytes, unless the stream is shorter)
+///
+/// Returns the read bytes and a stream that still yields everything, the read bytes included.
+pub async fn peek_prefix(
+    mut stream: BoxStream<'static, io::Result<Bytes>>,
+    limit: usize,
+) -> io::Result<(Bytes, BoxStream<'static, io::Result<Bytes>>)> {
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    while size < limit {
+        let Some(chunk) = stream.next().await else {
+            break;
+        };
+        let chunk = chunk?;
+        size += chunk.len();
+        chunks.push(chunk);
+    }
+
+    let prefix = Bytes::from(chunks.concat());
+    let stream = futures::stream::iter(chunks.into_iter().map(Ok))
+        .chain(stream)
+        .boxed();
+
+    Ok((prefix, stream))
+}
+
+/// Parses the `-progress` output of ffmpeg, calling `on_progress` with every complete progress block
+pub async fn pump_ffmpeg_stdout(
+    reader: impl AsyncBufRead,
+    mut on_progress: impl FnMut(&HashMap<String, String>),
+) -> Result<(), Whatever> {
+    pin!(reader);
+    let mut lines = reader.lines();
+
+    let mut progress: HashMap<String, String> = HashMap::new();
+
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .whatever_context("Reading ffmpeg stdout")?
+    {
+        let (k, v) = line.split_once('=').ok_or_else(|| {
+            Whatever::without_source("ffmpeg stdout was not k=v-formatted".to_owned())
+        })?;
+
+        if k == "progress" {
+            let fps = progress.get("fps").map(|v| v.as_str()).unwrap_or("");
+            let speed = progress.get("speed").map(|v| v.as_str()).unwrap_or("");
+            let out_time = progress.get("out_time").map(|v| v.as_str()).unwrap_or("");
+            trace!(
+                "ffmpeg(progress) fps={:5} speed={:5} out_time={}",
+                fps,
+                speed,
+                out_time
+            );
+            on_progress(&progress);
+        } else {
+            progress.insert(k.to_string(), v.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Runs ffmpeg that reads and writes files, reporting how much of the output is done
+///
+/// `-progress` is added to the `args` automatically.
+pub async fn run_ffmpeg_with_progress(
+    args: &[String],
+    mut on_progress: impl FnMut(Duration),
+) -> Result<(), Whatever> {
+    let mut child = Command::new(FFMPEG)
+        .args(["-hide_banner", "-loglevel", "error", "-nostats", "-y"])
+        .args(["-progress", "pipe:1"])
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .whatever_context("Starting ffmpeg")?;
+
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut stderr = child.stderr.take().unwrap();
+
+    let pump_stdout = pump_ffmpeg_stdout(stdout, |progress| {
+        // despite the name, `out_time_ms` is in microseconds too
+        if let Some(out_time) = progress
+            .get("out_time_us")
+            .or_else(|| progress.get("out_time_ms"))
+            .and_then(|v| v.parse::<u64>().ok())
+        {
+            on_progress(Duration::from_micros(out_time));
+        }
+    });
+    let read_stderr = async {
+        let mut errors = String::new();
+        let _ = stderr.read_to_string(&mut errors).await;
+        errors
+    };
+
+    let (pumped, errors, status) = tokio::join!(pump_stdout, read_stderr, child.wait());
+    let status = status.whatever_context("Waiting for ffmpeg")?;
+    if !status.success() {
+        whatever!("ffmpeg exited with {}: {}", status, errors.trim());
+    }
+    pumped
+}
+
+/// A temporary file, removed when dropped
+pub struct SpooledFile {
+    pub path: PathBuf,
+}
+
+impl SpooledFile {
+    /// Makes up a path for a new temporary file, the file itself is not created
+    ///
+    /// ffmpeg guesses the format by the `extension`.
+    pub fn new_temp(extension: &str) -> Self {
+        Self {
+            path: std::env::temp_dir().join(format!(
+                "shari-bot-{:016x}.{}",
+                rand::random::<u64>(),
+                extension
+            )),
+        }
+    }
+
+    /// Writes the whole video stream to a temporary file
+    pub async fn spool(mut stream: BoxStream<'static, io::Result<Bytes>>) -> io::Result<Self> {
+        // created before the file, so that the file is removed on any error
+        let spooled = Self::new_temp("mp4");
+
+        let mut file = tokio::fs::File::create(&spooled.path).await?;
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+
+        Ok(spooled)
+    }
+
+    /// Streams the file, removing it when the stream is dropped
+    ///
+    /// Returns the stream along with the file size.
+    pub async fn into_stream(self) -> io::Result<(BoxStream<'static, io::Result<Bytes>>, u64)> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        let size = file.metadata().await?.len();
+
+        let stream = ReaderStream::new(file)
+            .map(move |chunk| {
+                // keep the file around while it's being read
+                let _ = &self;
+                chunk
+            })
+            .boxed();
+
+        Ok((stream, size))
+    }
+
+    pub fn path_str(&self) -> String {
+        self.path.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {}
+            // the file was never created
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Could not remove the spooled file {:?}: {}", self.path, e),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Detects the codecs, dimensions and duration of the video with ffprobe
+//!
+//! Telegram needs the dimensions and the duration to play big files as streaming videos.
+
+use std::{io, time::Duration};
+
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use serde::Deserialize;
+use snafu::{OptionExt, ResultExt};
+use tracing::{debug, warn};
+
+use crate::{
+    downloader::VideoInformation,
+    media::{self, SpooledFile},
+    whatever::Whatever,
+};
+
+/// How much of the video is read before probing it
+///
+/// Enough for the `moov` box of a faststart mp4. If it's at the end, the whole file is spooled to disk.
+const PREFIX_SIZE: usize = 4 * 1024 * 1024;
+
+const PROBE_ARGS: &[&str] = &[
+    "-show_entries",
+    "stream=codec_type,codec_name,width,height,duration:format=duration",
+    "-of",
+    "json",
+];
+
+#[derive(Debug, Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<i32>,
+    height: Option<i32>,
+    duration: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+}
+
+/// What ffprobe found out about the video
+pub struct ProbeResult {
+    pub video_information: VideoInformation,
+    /// Codec names as reported by ffprobe, like `h264` or `vp9`
+    pub video_codec: Option<String>,
+    /// `None` if there's no audio
+    pub audio_codec: Option<String>,
+}
+
+fn parse_probe_output(output: &[u8]) -> Result<ProbeResult, Whatever> {
+    let output: ProbeOutput =
+        serde_json::from_slice(output).whatever_context("Parsing ffprobe output")?;
+    let find_stream = |codec_type: &str| {
+        output
+            .streams
+            .iter()
+            .find(|stream| stream.codec_type.as_deref() == Some(codec_type))
+    };
+
+    let video = find_stream("video").whatever_context("No video stream found")?;
+    let audio = find_stream("audio");
+
+    // ffprobe reports the numbers as strings
+    let duration = video
+        .duration
+        .as_deref()
+        .or(output.format.as_ref().and_then(|f| f.duration.as_deref()))
+        .and_then(|duration| duration.parse::<f64>().ok())
+        .whatever_context("No duration found")?;
+
+    Ok(ProbeResult {
+        video_information: VideoInformation {
+            width: video.width.whatever_context("No width found")?,
+            height: video.height.whatever_context("No height found")?,
+            duration: Duration::from_secs_f64(duration),
+        },
+        video_codec: video.codec_name.clone(),
+        audio_codec: audio.and_then(|audio| audio.codec_name.clone()),
+    })
+}
+
+async fn probe_bytes(input: Bytes) -> Result<ProbeResult, Whatever> {
+    let mut args = PROBE_ARGS.to_vec();
+    args.extend(["-i", "pipe:0"]);
+
+    let output = media::run_ffprobe(&args, input).await?;
+    parse_probe_output(&output)
+}
+
+pub async fn probe_file(file: &SpooledFile) -> Result<ProbeResult, Whatever> {
+    let path = file.path_str();
+    let mut args = PROBE_ARGS.to_vec();
+    args.extend(["-i", &path]);
+
+    let output = media::run_ffprobe(&args, Bytes::new()).await?;
+    parse_probe_output(&output)
+}
+
+/// Probes the video stream
+///
+/// Failing to probe is not an error, `None` is returned then.
+/// Returns the video stream that still yields all the bytes.
+pub async fn probe_stream(
+    stream: BoxStream<'static, io::Result<Bytes>>,
+) -> io::Result<(Option<ProbeResult>, BoxStream<'static, io::Result<Bytes>>)> {
+    let (prefix, stream) = media::peek_prefix(stream, PREFIX_SIZE).await?;
+    let is_whole_file = prefix.len() < PREFIX_SIZE;
+
+    match probe_bytes(prefix).await {
+        Ok(result) => return Ok((Some(result), stream)),
+        Err(e) if is_whole_file => {
+            warn!(
+                "Could not probe the video: {}",
+                snafu::Report::from_error(e)
+            );
+            return Ok((None, stream));
+        }
+        Err(e) => debug!(
+            "Could not probe the start of the video, probably the moov box is at the end: {}",
+            snafu::Report::from_error(e)
+        ),
+    }
+
+    debug!("Spooling the video to disk to probe it");
+    let spooled = SpooledFile::spool(stream).await?;
+    let result = match probe_file(&spooled).await {
+        Ok(result) => Some(result),
+        Err(e) => {
+            warn!(
+                "Could not probe the spooled video: {}",
+                snafu::Report::from_error(e)
+            );
+            None
+        }
+    };
+
+    let (stream, _) = spooled.into_stream().await?;
+    Ok((result, stream))
+}
@@ -0,0 +1,105 @@
+//! Makes the video thumbnails that telegram shows before the video is loaded
+
+use std::{io, time::Duration};
+
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use once_cell::sync::Lazy;
+use snafu::ResultExt;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{media, whatever::Whatever};
+
+/// How much of the video is read to extract a frame from
+///
+/// Should be enough to cover the `moov` box and the first keyframe of a faststart mp4.
+const PREFIX_SIZE: usize = 2 * 1024 * 1024;
+
+/// The thumbnail is not worth holding the upload for longer than this
+const IMAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shared by all the jobs, so that the connections to the image hosts are reused
+static IMAGE_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::ClientBuilder::new()
+        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/92.0.4515.115 Safari/537.36")
+        .timeout(IMAGE_TIMEOUT)
+        .build()
+        .expect("Building the thumbnail client")
+});
+
+/// Telegram requires the thumbnails to be JPEGs no larger than 320px on each side
+const THUMBNAIL_ARGS: &[&str] = &[
+    "-i",
+    "pipe:0",
+    "-frames:v",
+    "1",
+    "-vf",
+    "scale=320:320:force_original_aspect_ratio=decrease",
+    "-q:v",
+    "5",
+    "-f",
+    "image2",
+    "-c:v",
+    "mjpeg",
+    "pipe:1",
+];
+
+async fn from_image(url: &Url) -> Result<Bytes, Whatever> {
+    let image = IMAGE_CLIENT
+        .get(url.clone())
+        .send()
+        .await
+        .whatever_context("Requesting the thumbnail")?
+        .error_for_status()
+        .whatever_context("Thumbnail request failed")?
+        .bytes()
+        .await
+        .whatever_context("Downloading the thumbnail")?;
+
+    let thumbnail = media::run_ffmpeg(THUMBNAIL_ARGS, image)
+        .await
+        .whatever_context("Resizing the thumbnail")?;
+
+    Ok(thumbnail.into())
+}
+
+async fn from_video_prefix(prefix: Bytes) -> Result<Bytes, Whatever> {
+    let thumbnail = media::run_ffmpeg(THUMBNAIL_ARGS, prefix)
+        .await
+        .whatever_context("Extracting a frame")?;
+
+    Ok(thumbnail.into())
+}
+
+/// Makes the thumbnail from the image provided by the platform, or from the first frame of the video
+///
+/// Failing to make a thumbnail is not an error, the video is sent without one then.
+/// Returns the video stream that still yields all the bytes.
+pub async fn make_thumbnail(
+    thumbnail_url: Option<&Url>,
+    stream: BoxStream<'static, io::Result<Bytes>>,
+) -> io::Result<(Option<Bytes>, BoxStream<'static, io::Result<Bytes>>)> {
+    if let Some(thumbnail_url) = thumbnail_url {
+        match from_image(thumbnail_url).await {
+            Ok(thumbnail) => return Ok((Some(thumbnail), stream)),
+            Err(e) => warn!(
+                "Could not use the thumbnail provided by the platform: {}",
+                snafu::Report::from_error(e)
+            ),
+        }
+    }
+
+    debug!("Extracting a frame for the thumbnail");
+    let (prefix, stream) = media::peek_prefix(stream, PREFIX_SIZE).await?;
+    match from_video_prefix(prefix).await {
+        Ok(thumbnail) => Ok((Some(thumbnail), stream)),
+        Err(e) => {
+            warn!(
+                "Could not extract a frame for the thumbnail: {}",
+                snafu::Report::from_error(e)
+            );
+            Ok((None, stream))
+        }
+    }
+}
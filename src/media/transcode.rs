@@ -0,0 +1,181 @@
+//! Re-encodes the videos that telegram clients can't play inline, or that are too large, to H.264/AAC
+
+use std::{io, time::Duration};
+
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use snafu::{whatever, ResultExt};
+use tracing::debug;
+
+use crate::{
+    bot::{UploadNotifier, UploadStatus},
+    config,
+    media::{self, probe::ProbeResult, SpooledFile},
+    whatever::Whatever,
+};
+
+/// Returns true if the video or the audio codec is not in the allow-list
+pub fn needs_transcoding(config: &config::Transcoding, probed: &ProbeResult) -> bool {
+    let video_allowed = probed
+        .video_codec
+        .as_ref()
+        .map_or(false, |codec| config.allowed_video_codecs.contains(codec));
+    // silent videos are fine
+    let audio_allowed = probed
+        .audio_codec
+        .as_ref()
+        .map_or(true, |codec| config.allowed_audio_codecs.contains(codec));
+
+    !(video_allowed && audio_allowed)
+}
+
+/// Bitrate reserved for the audio when compressing
+const COMPRESSED_AUDIO_BITRATE: u64 = 96_000;
+/// Below this the video is not worth watching, so it's better to fail
+const MIN_VIDEO_BITRATE: u64 = 100_000;
+/// Part of the size limit left for the container overhead and the bitrate fluctuations
+const SIZE_MARGIN: f64 = 0.92;
+
+/// Side of the round video notes, telegram does not show them any larger
+pub const ROUND_VIDEO_SIZE: i32 = 640;
+
+/// How the video is re-encoded
+#[derive(Debug, Clone, Copy)]
+pub enum Encoding {
+    /// Constant quality, for the videos that only need a compatible codec
+    Compatible,
+    /// Constrained bitrate, so that the result fits into `max_size` bytes
+    FitSize { max_size: u64 },
+    /// Cropped to a square of at most [ROUND_VIDEO_SIZE], for the round video notes
+    Round,
+    /// Same video, without the audio
+    Silent,
+}
+
+/// H.264 with the most compatible pixel format (some sources use 10-bit ones)
+const H264_ARGS: &[&str] = &[
+    "-c:v", "libx264", "-preset", "veryfast", "-pix_fmt", "yuv420p",
+];
+
+fn encoding_args(encoding: Encoding, duration: Duration) -> Result<Vec<String>, Whatever> {
+    let h264 = H264_ARGS.iter().map(|arg| arg.to_string());
+
+    Ok(match encoding {
+        Encoding::Compatible => h264
+            .chain(["-crf", "23", "-c:a", "aac", "-b:a", "128k"].map(str::to_string))
+            .collect(),
+        Encoding::FitSize { max_size } => {
+            let total_bitrate =
+                (max_size as f64 * 8.0 * SIZE_MARGIN / duration.as_secs_f64().max(1.0)) as u64;
+            let video_bitrate = total_bitrate.saturating_sub(COMPRESSED_AUDIO_BITRATE);
+            if video_bitrate < MIN_VIDEO_BITRATE {
+                whatever!(
+                    "The video is too long to fit into {} bytes with a watchable quality",
+                    max_size
+                );
+            }
+            debug!("Compressing with the video bitrate of {}", video_bitrate);
+
+            h264.chain([
+                "-b:v".to_string(),
+                video_bitrate.to_string(),
+                "-maxrate".to_string(),
+                video_bitrate.to_string(),
+                "-bufsize".to_string(),
+                (video_bitrate * 2).to_string(),
+                "-c:a".to_string(),
+                "aac".to_string(),
+                "-b:a".to_string(),
+                COMPRESSED_AUDIO_BITRATE.to_string(),
+            ])
+            .collect()
+        }
+        Encoding::Round => h264
+            .chain([
+                "-vf".to_string(),
+                format!(
+                    "crop='min(iw,ih)':'min(iw,ih)',scale='min({0},iw)':'min({0},ih)'",
+                    ROUND_VIDEO_SIZE
+                ),
+                "-crf".to_string(),
+                "23".to_string(),
+                "-c:a".to_string(),
+                "aac".to_string(),
+                "-b:a".to_string(),
+                "128k".to_string(),
+            ])
+            .collect(),
+        Encoding::Silent => ["-c:v", "copy", "-an"].map(str::to_string).to_vec(),
+    })
+}
+
+/// Re-encodes the video to H.264/AAC, reporting the progress with [UploadStatus::Transcoding] or [UploadStatus::Compressing]
+///
+/// `burn_subtitles` are SRT subtitles to render into the video, only supported for [Encoding::Compatible].
+/// The video is spooled to disk, as ffmpeg needs to seek in the input and telegram needs to know the output size upfront.
+/// Returns the re-encoded video stream along with its size.
+pub async fn transcode(
+    stream: BoxStream<'static, io::Result<Bytes>>,
+    duration: Duration,
+    encoding: Encoding,
+    burn_subtitles: Option<&str>,
+    notifier: &UploadNotifier,
+) -> Result<(BoxStream<'static, io::Result<Bytes>>, u64), Whatever> {
+    let input = SpooledFile::spool(stream)
+        .await
+        .whatever_context("Spooling the video to disk")?;
+    let output = SpooledFile::new_temp("mp4");
+
+    let mut args = vec!["-i".to_string(), input.path_str()];
+    args.extend(encoding_args(encoding, duration)?);
+
+    // kept until ffmpeg is done
+    let subtitles_file = match burn_subtitles {
+        Some(srt) => {
+            let file = SpooledFile::new_temp("srt");
+            tokio::fs::write(&file.path, srt)
+                .await
+                .whatever_context("Writing the subtitles to disk")?;
+            args.extend([
+                "-vf".to_string(),
+                format!("subtitles='{}'", file.path_str()),
+            ]);
+            Some(file)
+        }
+        None => None,
+    };
+    args.extend(["-movflags", "+faststart", &output.path_str()].map(str::to_string));
+
+    debug!("Re-encoding the video with {:?}", encoding);
+    media::run_ffmpeg_with_progress(&args, |out_time| {
+        let progress = (out_time.as_secs_f32() / duration.as_secs_f32().max(1.0)).min(1.0);
+        let status = match encoding {
+            Encoding::FitSize { .. } => UploadStatus::Compressing { progress },
+            Encoding::Compatible | Encoding::Round | Encoding::Silent => {
+                UploadStatus::Transcoding { progress }
+            }
+        };
+        // the status message will just show the stale progress if it fails
+        let _ = notifier.notify_status(status);
+    })
+    .await
+    .whatever_context("Re-encoding the video")?;
+    drop(input);
+    drop(subtitles_file);
+
+    let (stream, size) = output
+        .into_stream()
+        .await
+        .whatever_context("Reading the re-encoded video")?;
+    if let Encoding::FitSize { max_size } = encoding {
+        if size > max_size {
+            whatever!(
+                "The compressed video is still too large ({} bytes, the limit is {})",
+                size,
+                max_size
+            );
+        }
+    }
+
+    Ok((stream, size))
+}
@@ -16,6 +16,8 @@ use tokio_util::codec::{BytesCodec, FramedRead};
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 use tracing::{info, trace};
 
+use crate::media::pump_ffmpeg_stdout;
+
 async fn write_stream(
     stream: impl Stream<Item = Result<Bytes, Whatever>>,
     dest: impl AsyncWrite,
@@ -32,32 +34,6 @@ async fn write_stream(
     Ok(())
 }
 
-async fn pump_ffmpeg_stdout(reader: impl AsyncBufRead) -> Result<()> {
-    pin!(reader);
-    let mut lines = reader.lines();
-
-    let mut progress: HashMap<String, String> = HashMap::new();
-
-    while let Some(line) = lines.next_line().await? {
-        let (k, v) = line.split_once('=').ok_or_else(|| {
-            Whatever::without_source("ffmpeg stdout was not k=v-formatted").to_owned()
-        })?;
-
-        if k == "progress" {
-            let fps = progress.get("fps").map(|v| v.as_str()).unwrap_or("");
-            let speed = progress.get("speed").map(|v| v.as_str()).unwrap_or("");
-            let out_time = progress.get("out_time").map(|v| v.as_str()).unwrap_or("");
-            info!(
-                "ffmpeg(progress) fps={:5} speed={:5} out_time={}",
-                fps, speed, out_time
-            )
-        } else {
-            progress.insert(k.to_string(), v.to_string());
-        }
-    }
-    Ok(())
-}
-
 async fn pump_ffmpeg_stderr(reader: impl AsyncBufRead) -> Result<()> {
     pin!(reader);
     let mut lines = reader.lines();
@@ -165,7 +141,7 @@ pub async fn remux(
 
     let pump_stdout = async {
         trace!("Starting stdout pump");
-        pump_ffmpeg_stdout(stdout)
+        pump_ffmpeg_stdout(stdout, |_| {})
             .await
             .whatever_context("Pumping ffmpeg stdout")
     };
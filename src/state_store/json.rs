@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use snafu::ResultExt;
+
+use super::{Collection, StateStore};
+use crate::{atomic_file, config, whatever::Whatever};
+
+/// Keeps every collection in its own JSON file, with rolling backups
+///
+/// The collections without a configured file are kept next to the whitelist, as `<name>.json`.
+pub struct JsonFileStore {
+    whitelist_file: PathBuf,
+    invites_file: PathBuf,
+}
+
+impl JsonFileStore {
+    pub fn new(config: &config::Data) -> Self {
+        let whitelist_file = PathBuf::from(&config.whitelist_file);
+        let invites_file = match &config.invites_file {
+            Some(invites_file) => PathBuf::from(invites_file),
+            None => whitelist_file.with_file_name(format!("{}.json", Collection::Invites.name())),
+        };
+        Self {
+            whitelist_file,
+            invites_file,
+        }
+    }
+
+    fn path(&self, collection: Collection) -> PathBuf {
+        match collection {
+            Collection::Whitelist => self.whitelist_file.clone(),
+            Collection::Invites => self.invites_file.clone(),
+            collection => self
+                .whitelist_file
+                .with_file_name(format!("{}.json", collection.name())),
+        }
+    }
+}
+
+#[async_trait]
+impl StateStore for JsonFileStore {
+    async fn load(&self, collection: Collection) -> Result<Option<Value>, Whatever> {
+        atomic_file::read(&self.path(collection), |data| {
+            serde_json::from_str(data)
+                .whatever_context("Deserializing JSON")
+                .map_err(atomic_file::ParseError::Corrupted)
+        })
+        .await
+    }
+
+    async fn store(&self, collection: Collection, value: &Value) -> Result<(), Whatever> {
+        let data = serde_json::to_vec(value).whatever_context("Serializing JSON")?;
+        atomic_file::write(&self.path(collection), &data)
+            .await
+            .whatever_context("Writing to file")
+    }
+}
@@ -0,0 +1,222 @@
+//! Where the bot keeps the state that has to survive the restarts
+
+mod json;
+mod sqlite;
+
+use std::{path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+pub use json::JsonFileStore;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use snafu::ResultExt;
+pub use sqlite::SqliteStore;
+use tracing::info;
+
+use crate::{config, whatever::Whatever};
+
+/// A piece of the state, always loaded and stored as a whole
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Collection {
+    Whitelist,
+    Invites,
+    /// Output mode set with `/mode` in each chat
+    ChatOutputModes,
+    /// Access hashes of the superusers, to send them the access requests
+    SuperuserChats,
+    /// Downloads of every user today, to enforce the daily limits of the roles
+    DownloadQuotas,
+}
+
+impl Collection {
+    pub const ALL: [Collection; 5] = [
+        Collection::Whitelist,
+        Collection::Invites,
+        Collection::ChatOutputModes,
+        Collection::SuperuserChats,
+        Collection::DownloadQuotas,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Whitelist => "whitelist",
+            Self::Invites => "invites",
+            Self::ChatOutputModes => "chat_output_modes",
+            Self::SuperuserChats => "superuser_chats",
+            Self::DownloadQuotas => "download_quotas",
+        }
+    }
+}
+
+#[async_trait]
+pub trait StateStore: Send + Sync {
+    /// Returns `None` if the collection was never stored
+    async fn load(&self, collection: Collection) -> Result<Option<Value>, Whatever>;
+
+    /// Replaces the stored collection, either completely or not at all
+    async fn store(&self, collection: Collection, value: &Value) -> Result<(), Whatever>;
+}
+
+impl dyn StateStore {
+    /// Loads the collection as `T`, the default value if it was never stored
+    pub async fn load_as<T: DeserializeOwned + Default>(
+        &self,
+        collection: Collection,
+    ) -> Result<T, Whatever> {
+        match self.load(collection).await? {
+            Some(value) => serde_json::from_value(value)
+                .with_whatever_context(|_| format!("Deserializing the {}", collection.name())),
+            None => Ok(T::default()),
+        }
+    }
+
+    pub async fn store_as<T: Serialize>(
+        &self,
+        collection: Collection,
+        value: &T,
+    ) -> Result<(), Whatever> {
+        let value = serde_json::to_value(value)
+            .with_whatever_context(|_| format!("Serializing the {}", collection.name()))?;
+        self.store(collection, &value).await
+    }
+}
+
+/// Opens the store selected in the config
+///
+/// The SQLite store imports the collections it doesn't have yet from the JSON files,
+/// so switching to it keeps the state. The JSON files are left in place after that, but not read anymore.
+pub async fn open(config: &config::Data) -> Result<Arc<dyn StateStore>, Whatever> {
+    let json = JsonFileStore::new(config);
+    match &config.state_store {
+        config::StateStoreKind::Json => Ok(Arc::new(json)),
+        config::StateStoreKind::Sqlite { path } => {
+            let sqlite = SqliteStore::open(PathBuf::from(path))
+                .await
+                .whatever_context("Opening the SQLite database")?;
+            import_missing(&json, &sqlite)
+                .await
+                .whatever_context("Importing the JSON files")?;
+            Ok(Arc::new(sqlite))
+        }
+    }
+}
+
+async fn import_missing(from: &dyn StateStore, to: &dyn StateStore) -> Result<(), Whatever> {
+    for collection in Collection::ALL {
+        if to.load(collection).await?.is_some() {
+            continue;
+        }
+        let Some(value) = from.load(collection).await? else {
+            continue;
+        };
+        info!("Importing the {} into the new store", collection.name());
+        to.store(collection, &value).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn data(dir: &TempDir, state_store: config::StateStoreKind) -> config::Data {
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        config::Data {
+            whitelist_file: path("whitelist.json"),
+            invites_file: Some(path("invites.json")),
+            state_store,
+        }
+    }
+
+    fn sqlite(dir: &TempDir) -> config::StateStoreKind {
+        config::StateStoreKind::Sqlite {
+            path: dir
+                .path()
+                .join("state.sqlite")
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    async fn round_trip(store: &dyn StateStore) {
+        for collection in Collection::ALL {
+            assert_eq!(store.load(collection).await.unwrap(), None);
+        }
+
+        let first = json!({ "version": 2, "users": {} });
+        let second = json!({ "code": { "uses_left": 1 } });
+        store.store(Collection::Whitelist, &first).await.unwrap();
+        store.store(Collection::Invites, &second).await.unwrap();
+        assert_eq!(
+            store.load(Collection::Whitelist).await.unwrap(),
+            Some(first)
+        );
+        assert_eq!(store.load(Collection::Invites).await.unwrap(), Some(second));
+
+        let replaced = json!([1, 2, 3]);
+        store.store(Collection::Invites, &replaced).await.unwrap();
+        assert_eq!(
+            store.load(Collection::Invites).await.unwrap(),
+            Some(replaced)
+        );
+    }
+
+    #[tokio::test]
+    async fn json_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let data = data(&dir, config::StateStoreKind::Json);
+        round_trip(&JsonFileStore::new(&data)).await;
+
+        // the configured files are used, the rest is kept next to the whitelist
+        assert!(dir.path().join("whitelist.json").exists());
+        assert!(dir.path().join("invites.json").exists());
+        let store = JsonFileStore::new(&data);
+        store
+            .store(Collection::ChatOutputModes, &json!({}))
+            .await
+            .unwrap();
+        assert!(dir.path().join("chat_output_modes.json").exists());
+    }
+
+    #[tokio::test]
+    async fn sqlite_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let store = SqliteStore::open(dir.path().join("state.sqlite"))
+            .await
+            .unwrap();
+        round_trip(&store).await;
+    }
+
+    #[tokio::test]
+    async fn imports_the_json_files_once() {
+        let dir = TempDir::new().unwrap();
+        let json = JsonFileStore::new(&data(&dir, config::StateStoreKind::Json));
+        let whitelist = json!({ "version": 2, "users": {} });
+        json.store(Collection::Whitelist, &whitelist).await.unwrap();
+
+        let store = open(&data(&dir, sqlite(&dir))).await.unwrap();
+        assert_eq!(
+            store.load(Collection::Whitelist).await.unwrap(),
+            Some(whitelist)
+        );
+        // nothing to import, so nothing is stored
+        assert_eq!(store.load(Collection::Invites).await.unwrap(), None);
+
+        // once the database has the collection, the JSON file is not imported again
+        let changed = json!({ "version": 2, "users": { "1": { "access_hash": 1 } } });
+        store.store(Collection::Whitelist, &changed).await.unwrap();
+        drop(store);
+        json.store(Collection::Whitelist, &json!({ "stale": true }))
+            .await
+            .unwrap();
+
+        let store = open(&data(&dir, sqlite(&dir))).await.unwrap();
+        assert_eq!(
+            store.load(Collection::Whitelist).await.unwrap(),
+            Some(changed)
+        );
+    }
+}
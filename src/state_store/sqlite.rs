@@ -0,0 +1,96 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use snafu::ResultExt;
+
+use super::{Collection, StateStore};
+use crate::whatever::Whatever;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = FULL;
+    CREATE TABLE IF NOT EXISTS state (
+        collection TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+";
+
+/// Keeps the collections as JSON in an embedded SQLite database, one row each
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub async fn open(path: PathBuf) -> Result<Self, Whatever> {
+        let connection = tokio::task::spawn_blocking(move || -> Result<_, Whatever> {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).whatever_context("Creating folder")?;
+            }
+            let connection = Connection::open(&path).whatever_context("Opening the database")?;
+            connection
+                .execute_batch(SCHEMA)
+                .whatever_context("Creating the tables")?;
+            Ok(connection)
+        })
+        .await
+        .whatever_context("Opening the database panicked")??;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs the queries off the async runtime, sqlite calls are blocking
+    async fn with_connection<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, Whatever> + Send + 'static,
+    ) -> Result<T, Whatever> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&connection.lock().unwrap()))
+            .await
+            .whatever_context("Database query panicked")?
+    }
+}
+
+#[async_trait]
+impl StateStore for SqliteStore {
+    async fn load(&self, collection: Collection) -> Result<Option<Value>, Whatever> {
+        let data = self
+            .with_connection(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT data FROM state WHERE collection = ?1",
+                        [collection.name()],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()
+                    .whatever_context("Querying the database")
+            })
+            .await?;
+
+        data.map(|data| serde_json::from_str(&data).whatever_context("Deserializing JSON"))
+            .transpose()
+    }
+
+    async fn store(&self, collection: Collection, value: &Value) -> Result<(), Whatever> {
+        let data = serde_json::to_string(value).whatever_context("Serializing JSON")?;
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO state (collection, data, updated_at) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (collection) DO UPDATE SET data = excluded.data, updated_at = excluded.updated_at",
+                    params![collection.name(), data, Utc::now().to_rfc3339()],
+                )
+                .whatever_context("Writing to the database")?;
+            Ok(())
+        })
+        .await
+    }
+}